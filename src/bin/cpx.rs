@@ -16,6 +16,74 @@ pub struct Cli {
     #[arg(short = 'n', long, env = "MODE_DRY_RUN", value_parser = clap::builder::FalseyValueParser::new())]
     dry_run: bool,
 
+    /// Don't copy extended attributes in these namespaces (comma-separated)
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "NAMESPACES")]
+    no_xattrs: Vec<mvx::XattrNamespace>,
+
+    /// Hard-link files instead of copying them
+    #[arg(short = 'l', long, conflicts_with = "symlink")]
+    link: bool,
+
+    /// With --link, copy files on another device instead of failing
+    #[arg(long, requires = "link")]
+    link_fallback: bool,
+
+    /// Symlink to files instead of copying them (absolute targets)
+    #[arg(short = 's', long)]
+    symlink: bool,
+
+    /// With --symlink, make link targets relative to the link's directory
+    #[arg(long, requires = "symlink")]
+    relative: bool,
+
+    /// When to clone files with copy-on-write instead of copying their data
+    #[arg(long, value_enum, default_value_t, value_name = "WHEN")]
+    reflink: mvx::ReflinkMode,
+
+    /// Bypass the page cache with O_DIRECT when copying file data
+    #[arg(long)]
+    direct: bool,
+
+    /// Flush copied files and directories to disk before finishing
+    #[arg(long)]
+    sync: bool,
+
+    /// Continue interrupted copies from their part files (with -f, also from a partial destination)
+    #[arg(long)]
+    resume: bool,
+
+    /// Number of files within a directory to transfer in parallel
+    #[arg(short = 'j', long, default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), value_name = "N")]
+    jobs: usize,
+
+    /// Copy each large file as N concurrent streams over separate byte ranges (at most 64)
+    #[arg(long, default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=64), value_name = "N")]
+    streams: usize,
+
+    /// Size of each copy buffer, e.g. 256K or 4M
+    #[arg(long, default_value = "1M", value_parser = mvx::parse_size, value_name = "SIZE")]
+    buffer_size: usize,
+
+    /// Number of copy buffers, so reading can run ahead of writing
+    #[arg(long, default_value_t = 4, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), value_name = "N")]
+    buffer_count: usize,
+
+    /// How to handle holes in sparse files when data has to be copied
+    #[arg(long, value_enum, default_value_t, value_name = "WHEN")]
+    sparse: mvx::SparseMode,
+
+    /// Never follow symlinks in sources (default)
+    #[arg(short = 'P', long = "no-dereference", overrides_with_all = ["dereference", "dereference_command_line"])]
+    no_dereference: bool,
+
+    /// Follow all symlinks in sources
+    #[arg(short = 'L', long, overrides_with_all = ["no_dereference", "dereference_command_line"])]
+    dereference: bool,
+
+    /// Follow symlinks given as sources, but not ones found inside directories
+    #[arg(short = 'H', overrides_with_all = ["no_dereference", "dereference"])]
+    dereference_command_line: bool,
+
     /// Paths to copy from
     #[arg(required = true)]
     srcs: Vec<PathBuf>,
@@ -35,6 +103,34 @@ fn main() {
         force: cli.force,
         dry_run: cli.dry_run,
         batch_size: cli.srcs.len(),
+        skip_xattrs: &cli.no_xattrs,
+        deref: if cli.dereference {
+            mvx::Dereference::Always
+        } else if cli.dereference_command_line {
+            mvx::Dereference::CommandLine
+        } else {
+            mvx::Dereference::Never
+        },
+        sparse: cli.sparse,
+        direct: cli.direct,
+        sync: cli.sync,
+        resume: cli.resume,
+        buffer_size: cli.buffer_size,
+        buffer_count: cli.buffer_count,
+        jobs: cli.jobs,
+        streams: cli.streams,
+        reflink: cli.reflink,
+        link: if cli.link {
+            mvx::LinkMode::Hard {
+                copy_fallback: cli.link_fallback,
+            }
+        } else if cli.symlink {
+            mvx::LinkMode::Symbolic {
+                relative: cli.relative,
+            }
+        } else {
+            mvx::LinkMode::Off
+        },
         mp: &mp,
         ctrlc: &ctrlc,
     };
@@ -1,9 +1,20 @@
 use crate::{
-    Ctx, FAIL_MARK, MoveOrCopy, SourceKind, TransferStats, item_progress_bar, message_with_arrow,
+    Conflict, Ctx, Dereference, FAIL_MARK, MoveOrCopy, SourceKind, TransferStats,
+    file::{create_dirs, same_file, sync_parent, sync_path},
+    file_size, is_cancelled, is_dir, item_progress_bar, message_with_arrow, meta, metadata,
 };
-use anyhow::{Context, ensure};
+use anyhow::{Context, bail, ensure};
 use colored::Colorize;
-use std::{fs, path::Path, sync::atomic::Ordering};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+};
 
 /// Check whether `src` and `dest` reside on the same filesystem.
 /// If `dest` doesn't exist, walks up to its nearest existing ancestor.
@@ -18,11 +29,260 @@ pub(crate) fn same_device(src: &Path, dest: &Path) -> bool {
         .is_some_and(|m| m.dev() == src_dev)
 }
 
-pub(crate) fn merge_or_copy<Src: AsRef<Path>, Dest: AsRef<Path>, F: Fn(u64)>(
+/// Resolve `path` to an absolute, symlink-free path even if it doesn't exist
+/// yet, by canonicalizing its nearest existing ancestor and re-appending the rest.
+pub(crate) fn canonicalize_lossy(path: &Path) -> PathBuf {
+    let mut tail = Vec::new();
+    let mut cur = path;
+    loop {
+        if let Ok(canonical) = cur.canonicalize() {
+            return tail
+                .iter()
+                .rev()
+                .fold(canonical, |acc, name| acc.join(name));
+        }
+        match (cur.parent(), cur.file_name()) {
+            (Some(parent), Some(name)) => {
+                tail.push(name);
+                cur = if parent.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    parent
+                };
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+/// Fail if `dest` lies strictly inside directory `src`, which would make a
+/// merge recurse into its own output (copy) or rename a directory into itself (move).
+pub(crate) fn ensure_not_inside(src: &Path, dest: &Path) -> anyhow::Result<()> {
+    let src_canonical = canonicalize_lossy(src);
+    let dest_canonical = canonicalize_lossy(dest);
+    ensure!(
+        dest_canonical == src_canonical || !dest_canonical.starts_with(&src_canonical),
+        "Destination '{}' is inside source directory '{}'",
+        dest.display(),
+        src.display()
+    );
+    Ok(())
+}
+
+/// (dev, inode) of a directory, to recognize it when reached again through a symlink.
+fn dir_id(meta: &fs::Metadata) -> (u64, u64) {
+    (meta.dev(), meta.ino())
+}
+
+/// Entries of directory `src` in the order a merge handles them.
+fn sorted_entries(src: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut entries: Vec<_> = fs::read_dir(src)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
+
+/// Callbacks for `walk_tree`.
+trait TreeVisitor {
+    /// Visit source entry `src`, which a merge would put at `dest`. Returns
+    /// whether to descend into it, if it's a directory.
+    fn visit(&mut self, src: &Path, dest: &Path, meta: &fs::Metadata) -> bool;
+
+    /// `src` is a followed symlink back to a directory it is inside of, and
+    /// isn't descended into.
+    fn symlink_loop(&mut self, _src: &Path, _dest: &Path) {}
+}
+
+/// Walk `src` without touching it the way merging it into `dest` would:
+/// entries in sorted order, symlinks followed as `deref` says (a dangling
+/// one is visited as the link itself), and unreadable entries skipped.
+fn walk_tree(src: &Path, dest: &Path, deref: Dereference, visitor: &mut impl TreeVisitor) {
+    fn walk(
+        src: &Path,
+        dest: &Path,
+        follow: bool,
+        deref: Dereference,
+        ancestors: &mut Vec<(u64, u64)>,
+        visitor: &mut impl TreeVisitor,
+    ) {
+        let Ok(meta) = metadata(src, follow).or_else(|_| fs::symlink_metadata(src)) else {
+            return;
+        };
+        if meta.is_dir() && ancestors.contains(&dir_id(&meta)) {
+            visitor.symlink_loop(src, dest);
+            return;
+        }
+        if !visitor.visit(src, dest, &meta) || !meta.is_dir() {
+            return;
+        }
+        ancestors.push(dir_id(&meta));
+        for entry in sorted_entries(src).unwrap_or_default() {
+            let dest_entry = dest.join(entry.file_name().unwrap());
+            walk(
+                &entry,
+                &dest_entry,
+                deref.nested(),
+                deref,
+                ancestors,
+                visitor,
+            );
+        }
+        ancestors.pop();
+    }
+    walk(
+        src,
+        dest,
+        deref.top_level(),
+        deref,
+        &mut Vec::new(),
+        visitor,
+    );
+}
+
+/// Walk `src` against `dest` without touching either, collecting every entry
+/// that would stop the merge partway: a directory landing on a file, a file
+/// landing on a directory, (without `force`) a file landing on a file, an
+/// entry landing on itself, or a followed symlink looping back up the tree.
+pub(crate) fn find_conflicts(
+    src: &Path,
+    dest: &Path,
+    force: bool,
+    deref: Dereference,
+) -> Vec<Conflict> {
+    let mut scan = ConflictScan {
+        force,
+        deref,
+        conflicts: Vec::new(),
+    };
+    walk_tree(src, dest, deref, &mut scan);
+    scan.conflicts
+}
+
+struct ConflictScan {
+    force: bool,
+    deref: Dereference,
+    conflicts: Vec<Conflict>,
+}
+
+impl TreeVisitor for ConflictScan {
+    fn visit(&mut self, src: &Path, dest: &Path, meta: &fs::Metadata) -> bool {
+        if fs::symlink_metadata(dest).is_err() {
+            // Nothing to collide with below, but followed links can still loop.
+            return self.deref.nested();
+        }
+        if same_file(src, dest) {
+            self.conflicts
+                .push(Conflict::SameFile(src.into(), dest.into()));
+            return false;
+        }
+        match (meta.is_dir(), dest.is_dir()) {
+            (true, true) => return true,
+            (true, false) => self
+                .conflicts
+                .push(Conflict::DirOverFile(src.into(), dest.into())),
+            (false, true) => self
+                .conflicts
+                .push(Conflict::FileOverDir(src.into(), dest.into())),
+            (false, false) if !self.force => {
+                self.conflicts
+                    .push(Conflict::FileOverFile(src.into(), dest.into()));
+            }
+            (false, false) => {}
+        }
+        false
+    }
+
+    fn symlink_loop(&mut self, src: &Path, _dest: &Path) {
+        self.conflicts.push(Conflict::SymlinkLoop(src.into()));
+    }
+}
+
+/// Every path merging `src` into `dest` would fill in, as (destination,
+/// source entry, is a directory), in walk order.
+pub(crate) fn planned_dests(
+    src: &Path,
+    dest: &Path,
+    deref: Dereference,
+) -> Vec<(PathBuf, PathBuf, bool)> {
+    struct Planned(Vec<(PathBuf, PathBuf, bool)>);
+    impl TreeVisitor for Planned {
+        fn visit(&mut self, src: &Path, dest: &Path, meta: &fs::Metadata) -> bool {
+            self.0
+                .push((dest.to_path_buf(), src.to_path_buf(), meta.is_dir()));
+            true
+        }
+    }
+
+    let mut planned = Planned(Vec::new());
+    walk_tree(src, dest, deref, &mut planned);
+    planned.0
+}
+
+/// Destination path of the first copy of each multiply-linked source file,
+/// keyed by its (dev, inode), so later links become hard links. Shared by
+/// every source in a batch.
+#[derive(Default)]
+pub(crate) struct LinkMap(HashMap<(u64, u64), PathBuf>);
+
+impl LinkMap {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hard-links `dest` to the first copy of `src` if one was recorded.
+    /// Otherwise calls `transfer`, telling it whether `src` has other links,
+    /// and records `dest` as the first copy if so. `transfer` may only put
+    /// the file off (e.g. for the worker pool) when it has no other links.
+    pub(crate) fn link_or_transfer<T: From<(String, TransferStats)>>(
+        &mut self,
+        src: &Path,
+        dest: &Path,
+        follow: bool,
+        ctx: &Ctx,
+        transfer: impl FnOnce(bool) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        // Moving a link away drops the inode's nlink, so look up every
+        // file, not just ones that are still multiply linked.
+        let meta = metadata(src, follow).ok().filter(fs::Metadata::is_file);
+        let id = meta.as_ref().map(|m| (m.dev(), m.ino()));
+        if let Some(first) = id.and_then(|id| self.0.get(&id)) {
+            match crate::file::link_to_copy(src, first, dest, follow, ctx) {
+                Ok(result) => return Ok(result.into()),
+                Err(e) => log::debug!(
+                    "Hard-linking '{}' failed, copying instead: {e:?}",
+                    dest.display()
+                ),
+            }
+        }
+        let linked = meta.as_ref().is_some_and(|m| m.nlink() > 1);
+        let result = transfer(linked)?;
+        if let Some(id) = id.filter(|_| linked) {
+            self.0.entry(id).or_insert_with(|| dest.to_path_buf());
+        }
+        Ok(result)
+    }
+}
+
+/// State threaded through a recursive merge.
+struct Walk<'a> {
+    /// (dev, inode) of every directory on the current path, so following a
+    /// symlink back to one of them errors out instead of recursing forever.
+    ancestors: Vec<(u64, u64)>,
+    links: &'a mut LinkMap,
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_symlink())
+}
+
+pub(crate) fn merge_or_copy<Src: AsRef<Path>, Dest: AsRef<Path>, F: Fn(u64) + Sync>(
     src: Src,
     dest: Dest,
     batch_cb: F,
     ctx: &Ctx,
+    links: &mut LinkMap,
 ) -> anyhow::Result<(String, TransferStats)> {
     let src = src.as_ref();
     let dest = dest.as_ref();
@@ -33,9 +293,11 @@ pub(crate) fn merge_or_copy<Src: AsRef<Path>, Dest: AsRef<Path>, F: Fn(u64)>(
         ctx.moc,
     );
 
-    ensure!(src.exists(), "Source '{}' does not exist", src.display());
+    let Ok(src_meta) = metadata(src, ctx.deref.top_level()) else {
+        bail!("Source '{}' does not exist", src.display());
+    };
     ensure!(
-        src.is_dir(),
+        src_meta.is_dir(),
         "Source '{}' exists but is not a directory",
         src.display()
     );
@@ -47,22 +309,44 @@ pub(crate) fn merge_or_copy<Src: AsRef<Path>, Dest: AsRef<Path>, F: Fn(u64)>(
             dest.display()
         );
     }
+    ensure_not_inside(src, dest)?;
+
+    // Moving through a followed symlink copies what it points to and removes
+    // only the link, so the target's contents are never moved out from under it.
+    let via_link = is_symlink(src);
+    let copy_ctx = Ctx {
+        moc: MoveOrCopy::Copy,
+        ..*ctx
+    };
+    let walk_ctx = if via_link { &copy_ctx } else { ctx };
 
     let timer = std::time::Instant::now();
-    let skip_sizing = matches!(ctx.moc, MoveOrCopy::Move) && same_device(src, dest);
+    let skip_sizing =
+        matches!(walk_ctx.moc, MoveOrCopy::Move) && !ctx.deref.nested() && same_device(src, dest);
 
     let pb = if skip_sizing {
         indicatif::ProgressBar::hidden()
     } else {
-        let total_size = collect_total_size(src);
+        let total_size = collect_total_size(src, ctx.deref.nested());
         ctx.mp
             .add(item_progress_bar(total_size, src, dest, ctx.moc))
     };
 
-    let stats = merge_or_copy_recursive(src, dest, ctx, &pb, &batch_cb)?;
+    let mut walk = Walk {
+        ancestors: Vec::new(),
+        links,
+    };
+    let stats = merge_or_copy_recursive(src, dest, walk_ctx, &pb, &batch_cb, &mut walk)?;
+    if ctx.sync {
+        sync_parent(dest)?;
+    }
 
     if matches!(ctx.moc, MoveOrCopy::Move) {
-        let _ = fs::remove_dir(src);
+        if via_link {
+            fs::remove_file(src)?;
+        } else {
+            let _ = fs::remove_dir(src);
+        }
     }
     pb.finish_and_clear();
 
@@ -72,23 +356,33 @@ pub(crate) fn merge_or_copy<Src: AsRef<Path>, Dest: AsRef<Path>, F: Fn(u64)>(
     ))
 }
 
-fn merge_or_copy_recursive<F: Fn(u64)>(
+fn merge_or_copy_recursive<F: Fn(u64) + Sync>(
     src: &Path,
     dest: &Path,
     ctx: &Ctx,
     pb: &indicatif::ProgressBar,
     batch_cb: &F,
+    walk: &mut Walk<'_>,
 ) -> anyhow::Result<TransferStats> {
-    // Fast path: move to non-existent dest — single rename
-    if matches!(ctx.moc, MoveOrCopy::Move) && !dest.exists() {
+    ensure!(
+        !same_file(src, dest),
+        "'{}' and '{}' are the same directory",
+        src.display(),
+        dest.display()
+    );
+    let via_link = is_symlink(src);
+
+    // Fast path: move to non-existent dest — single rename. Not when links
+    // inside must be followed, since a rename would carry them over as links.
+    if matches!(ctx.moc, MoveOrCopy::Move) && !via_link && !ctx.deref.nested() && !dest.exists() {
         if let Some(parent) = dest.parent().filter(|p| !p.exists()) {
-            fs::create_dir_all(parent)
+            create_dirs(parent, ctx.sync)
                 .with_context(|| format!("creating parent directory '{}'", parent.display()))?;
         }
         match fs::rename(src, dest) {
             Ok(()) => {
                 if !pb.is_hidden() {
-                    pb.inc(collect_total_size(dest));
+                    pb.inc(collect_total_size(dest, ctx.deref.nested()));
                     batch_cb(pb.position());
                 }
                 return Ok(TransferStats {
@@ -111,93 +405,248 @@ fn merge_or_copy_recursive<F: Fn(u64)>(
         }
     }
 
-    if !dest.exists() {
-        fs::create_dir_all(dest)
+    // Read before populating: a move empties `src`, which bumps its mtime.
+    let src_meta = fs::metadata(src).with_context(|| format!("reading '{}'", src.display()))?;
+    ensure!(
+        !walk.ancestors.contains(&dir_id(&src_meta)),
+        "Symlink loop: '{}' leads back to a directory it is inside of",
+        src.display()
+    );
+    let created = !dest.exists();
+    if created {
+        // Synced before anything lands inside, so no synced file sits under
+        // an entry that could still be lost.
+        create_dirs(dest, ctx.sync)
             .with_context(|| format!("creating directory '{}'", dest.display()))?;
     }
 
-    let mut entries: Vec<_> = fs::read_dir(src)
-        .with_context(|| format!("reading directory '{}'", src.display()))?
-        .filter_map(Result::ok)
-        .map(|e| e.path())
-        .collect();
-    entries.sort();
+    let entries =
+        sorted_entries(src).with_context(|| format!("reading directory '{}'", src.display()))?;
 
+    walk.ancestors.push(dir_id(&src_meta));
+    let follow = ctx.deref.nested();
     let mut stats = TransferStats::default();
-    let mut msgs: Vec<String> = Vec::new();
-    for entry in entries {
+    // One slot per entry, so messages keep directory order however files finish.
+    let mut msgs: Vec<Option<String>> = vec![None; entries.len()];
+    // Plain files left for the worker pool with `-j`, by index into `entries`.
+    let mut pending = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
         let name = entry.file_name().unwrap();
         let dest_entry = dest.join(name);
 
         if ctx.ctrlc.load(Ordering::Relaxed) {
-            for msg in &msgs {
-                log::info!("{msg}");
-            }
-            log::error!(
-                "{FAIL_MARK} Cancelled: {}",
-                message_with_arrow(&entry, &dest_entry, ctx.moc, true)
-            );
-            pb.abandon_with_message(
-                format!("{FAIL_MARK} {}", pb.message())
-                    .red()
-                    .bold()
-                    .to_string(),
-            );
-            std::process::exit(130);
+            cancel(&msgs, entry, &dest_entry, ctx, pb);
         }
 
-        if entry.is_dir() {
-            stats += merge_or_copy_recursive(&entry, &dest_entry, ctx, pb, batch_cb)?;
-            if matches!(ctx.moc, MoveOrCopy::Move) {
-                let _ = fs::remove_dir(&entry);
+        if is_dir(entry, follow) {
+            if is_symlink(entry) && matches!(ctx.moc, MoveOrCopy::Move) {
+                let copy_ctx = Ctx {
+                    moc: MoveOrCopy::Copy,
+                    ..*ctx
+                };
+                stats +=
+                    merge_or_copy_recursive(entry, &dest_entry, &copy_ctx, pb, batch_cb, walk)?;
+                fs::remove_file(entry)?;
+            } else {
+                stats += merge_or_copy_recursive(entry, &dest_entry, ctx, pb, batch_cb, walk)?;
+                if matches!(ctx.moc, MoveOrCopy::Move) {
+                    let _ = fs::remove_dir(entry);
+                }
             }
         } else {
-            let file_size = fs::metadata(&entry).map(|m| m.len()).unwrap_or(0);
-            let init_pos = pb.position();
-            let (msg, file_stats) = crate::file::move_or_copy(
-                &entry,
-                &dest_entry,
-                |copied_bytes: u64| {
-                    pb.set_position(init_pos + copied_bytes);
-                    batch_cb(init_pos + copied_bytes);
-                },
-                ctx,
-            )
-            .with_context(|| message_with_arrow(&entry, &dest_entry, ctx.moc, false))?;
-            // Snap to correct position after completion (handles fast-path
-            // where rename/reflink succeeds without calling progress_cb).
-            let final_pos = init_pos + file_size;
-            pb.set_position(final_pos);
-            batch_cb(final_pos);
-            stats += file_stats;
-            msgs.push(msg);
+            // Multiply-linked files stay serial, so the first copy is
+            // recorded before any other link to it comes up.
+            let result =
+                walk.links
+                    .link_or_transfer(entry, &dest_entry, follow, ctx, |linked| {
+                        if ctx.jobs > 1 && !linked {
+                            pending.push((i, dest_entry.clone()));
+                            return Ok(None);
+                        }
+                        match transfer_file(entry, &dest_entry, follow, ctx, pb, batch_cb) {
+                            Err(e) if is_cancelled(&e) => {
+                                cancel(&msgs, entry, &dest_entry, ctx, pb)
+                            }
+                            result => result.map(Some),
+                        }
+                    })?;
+            if let Some((msg, file_stats)) = result {
+                stats += file_stats;
+                msgs[i] = Some(msg);
+            }
+        }
+    }
+    walk.ancestors.pop();
+
+    let results = transfer_files_parallel(&entries, &pending, follow, ctx, pb, batch_cb);
+    let mut first_err = None;
+    for ((i, _), result) in pending.iter().zip(results) {
+        match result {
+            Some(Ok((msg, file_stats))) => {
+                stats += file_stats;
+                msgs[*i] = Some(msg);
+            }
+            // Reported below along with files that never started.
+            Some(Err(e)) if is_cancelled(&e) => {}
+            Some(Err(e)) => {
+                first_err.get_or_insert(e);
+            }
+            None => {}
         }
     }
+    if let Some(e) = first_err {
+        return Err(e);
+    }
+    if ctx.ctrlc.load(Ordering::Relaxed)
+        && let Some((i, dest_entry)) = pending.iter().find(|(i, _)| msgs[*i].is_none())
+    {
+        cancel(&msgs, &entries[*i], dest_entry, ctx, pb);
+    }
+
+    // Applied last: a read-only source mode can't block populating the copy,
+    // and adding entries no longer bumps the restored mtime.
+    if created {
+        let meta_src = if via_link {
+            fs::canonicalize(src)?
+        } else {
+            src.to_path_buf()
+        };
+        meta::copy_metadata(&meta_src, &src_meta, dest, ctx.skip_xattrs)
+            .with_context(|| format!("preserving metadata on '{}'", dest.display()))?;
+    }
+    // Before the caller removes the emptied `src`. Its parent is synced in
+    // turn once this returns, up to `merge_or_copy`.
+    if ctx.sync {
+        sync_path(dest).with_context(|| format!("syncing '{}'", dest.display()))?;
+    }
     Ok(stats)
 }
 
-pub(crate) fn collect_total_size(dir: &Path) -> u64 {
-    fs::read_dir(dir)
-        .into_iter()
-        .flatten()
-        .filter_map(Result::ok)
-        .map(|e| e.path())
-        .map(|path| {
-            if path.is_dir() {
-                collect_total_size(&path)
-            } else {
-                fs::metadata(&path).map(|m| m.len()).unwrap_or(0)
+/// Move or copy one file within a merge, advancing the shared progress bar by
+/// however much of it has been transferred. Safe to run on several files at once.
+fn transfer_file<F: Fn(u64) + Sync>(
+    entry: &Path,
+    dest_entry: &Path,
+    follow: bool,
+    ctx: &Ctx,
+    pb: &indicatif::ProgressBar,
+    batch_cb: &F,
+) -> anyhow::Result<(String, TransferStats)> {
+    let size = file_size(entry, follow);
+    let reported = AtomicU64::new(0);
+    let progress = |copied_bytes: u64| {
+        let prev = reported.swap(copied_bytes, Ordering::Relaxed);
+        pb.inc(copied_bytes.saturating_sub(prev));
+        batch_cb(pb.position());
+    };
+    let result = crate::file::move_or_copy(entry, dest_entry, progress, follow, ctx)
+        .with_context(|| message_with_arrow(entry, dest_entry, ctx.moc, false))?;
+    // Snap to the full size (handles fast-path where rename/reflink succeeds
+    // without calling progress_cb).
+    progress(size.max(reported.load(Ordering::Relaxed)));
+    Ok(result)
+}
+
+/// Transfer the `pending` entries (index into `entries`, destination) on
+/// `ctx.jobs` worker threads. Each result lines up with its `pending` entry;
+/// `None` means it was never started, because of Ctrl-C or an earlier error.
+fn transfer_files_parallel<F: Fn(u64) + Sync>(
+    entries: &[PathBuf],
+    pending: &[(usize, PathBuf)],
+    follow: bool,
+    ctx: &Ctx,
+    pb: &indicatif::ProgressBar,
+    batch_cb: &F,
+) -> Vec<Option<anyhow::Result<(String, TransferStats)>>> {
+    let results = Mutex::new(
+        std::iter::repeat_with(|| None)
+            .take(pending.len())
+            .collect::<Vec<_>>(),
+    );
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0..ctx.jobs.min(pending.len()) {
+            scope.spawn(|| {
+                // No new files start after Ctrl-C or a failure; in-flight ones are
+                // cancelled by Ctrl-C but otherwise finish.
+                while !ctx.ctrlc.load(Ordering::Relaxed) && !failed.load(Ordering::Relaxed) {
+                    let job = next.fetch_add(1, Ordering::Relaxed);
+                    let Some((i, dest_entry)) = pending.get(job) else {
+                        break;
+                    };
+                    let result = transfer_file(&entries[*i], dest_entry, follow, ctx, pb, batch_cb);
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    results.lock().unwrap()[job] = Some(result);
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// Report what finished before Ctrl-C, and `entry` as where it stopped, then exit.
+fn cancel(
+    msgs: &[Option<String>],
+    entry: &Path,
+    dest_entry: &Path,
+    ctx: &Ctx,
+    pb: &indicatif::ProgressBar,
+) -> ! {
+    for msg in msgs.iter().flatten() {
+        log::info!("{msg}");
+    }
+    log::error!(
+        "{FAIL_MARK} Cancelled: {}",
+        message_with_arrow(entry, dest_entry, ctx.moc, true)
+    );
+    pb.abandon_with_message(
+        format!("{FAIL_MARK} {}", pb.message())
+            .red()
+            .bold()
+            .to_string(),
+    );
+    std::process::exit(130);
+}
+
+/// Total bytes of regular files under `dir`, counting hard-linked files once.
+/// With `follow`, symlinks are counted as what they point to, skipping any
+/// that loop back to an ancestor.
+pub(crate) fn collect_total_size(dir: &Path, follow: bool) -> u64 {
+    #[derive(Default)]
+    struct Size {
+        total: u64,
+        linked: HashSet<(u64, u64)>,
+    }
+    impl TreeVisitor for Size {
+        fn visit(&mut self, _src: &Path, _dest: &Path, meta: &fs::Metadata) -> bool {
+            if meta.is_file() && (meta.nlink() == 1 || self.linked.insert((meta.dev(), meta.ino())))
+            {
+                self.total += meta.len();
             }
-        })
-        .sum()
+            true
+        }
+    }
+
+    let deref = if follow {
+        Dereference::Always
+    } else {
+        Dereference::CommandLine
+    };
+    let mut size = Size::default();
+    walk_tree(dir, dir, deref, &mut size);
+    size.total
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::LinkMode;
     use crate::tests::{
         assert_error_with_msg, assert_file_copied, assert_file_moved, create_temp_file,
-        hidden_multi_progress, noop_ctrlc,
+        hidden_multi_progress, is_root, noop_ctrlc, skip_unless, test_ctx,
     };
     use tempfile::tempdir;
 
@@ -210,14 +659,25 @@ mod tests {
         let mp = hidden_multi_progress();
         let ctrlc = noop_ctrlc();
         let ctx = Ctx {
-            moc,
             force,
-            dry_run: false,
-            batch_size: 1,
-            mp: &mp,
-            ctrlc: &ctrlc,
+            ..test_ctx(moc, &mp, &ctrlc)
+        };
+        merge_or_copy(src, dest, |_| {}, &ctx, &mut LinkMap::new()).map(|(msg, _)| msg)
+    }
+
+    fn _merge_or_copy_deref<Src: AsRef<Path>, Dest: AsRef<Path>>(
+        src: Src,
+        dest: Dest,
+        moc: MoveOrCopy,
+        deref: Dereference,
+    ) -> anyhow::Result<String> {
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            deref,
+            ..test_ctx(moc, &mp, &ctrlc)
         };
-        merge_or_copy(src, dest, |_| {}, &ctx).map(|(msg, _)| msg)
+        merge_or_copy(src, dest, |_| {}, &ctx, &mut LinkMap::new()).map(|(msg, _)| msg)
     }
 
     #[test]
@@ -304,10 +764,41 @@ mod tests {
         assert!(dest_dir.path().join("subdir/file2").exists());
     }
 
+    #[test]
+    fn move_across_devices_with_sync() {
+        let src_dir = tempdir().unwrap();
+        let dest_dir = tempfile::tempdir_in("/dev/shm").ok();
+        skip_unless!(dest_dir.is_some(), "needs /dev/shm");
+        let dest_dir = dest_dir.unwrap();
+        create_temp_file(src_dir.path(), "file1", "content");
+        create_temp_file(src_dir.path(), "subdir/file2", "content2");
+        std::os::unix::fs::symlink("file1", src_dir.path().join("link")).unwrap();
+        let src_path = src_dir.path().to_path_buf();
+        let dest_path = dest_dir.path().join("dest");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            sync: true,
+            ..test_ctx(MoveOrCopy::Move, &mp, &ctrlc)
+        };
+        merge_or_copy(&src_path, &dest_path, |_| {}, &ctx, &mut LinkMap::new()).unwrap();
+
+        assert!(!src_path.exists());
+        assert_eq!(
+            fs::read_to_string(dest_path.join("link")).unwrap(),
+            "content"
+        );
+        assert_eq!(
+            fs::read_to_string(dest_path.join("subdir/file2")).unwrap(),
+            "content2"
+        );
+    }
+
     #[test]
     fn collect_total_size_empty() {
         let temp_dir = tempdir().unwrap();
-        assert_eq!(collect_total_size(temp_dir.path()), 0);
+        assert_eq!(collect_total_size(temp_dir.path(), false), 0);
     }
 
     #[test]
@@ -315,7 +806,7 @@ mod tests {
         let temp_dir = tempdir().unwrap();
         create_temp_file(temp_dir.path(), "file1", "abc");
         create_temp_file(temp_dir.path(), "subdir/file2", "defgh");
-        assert_eq!(collect_total_size(temp_dir.path()), 8);
+        assert_eq!(collect_total_size(temp_dir.path(), false), 8);
     }
 
     #[test]
@@ -475,15 +966,9 @@ mod tests {
 
         let mp = hidden_multi_progress();
         let ctrlc = noop_ctrlc();
-        let ctx = Ctx {
-            moc: MoveOrCopy::Move,
-            force: false,
-            dry_run: false,
-            batch_size: 1,
-            mp: &mp,
-            ctrlc: &ctrlc,
-        };
-        let (msg, stats) = merge_or_copy(&src_dir, &dest_dir, |_| {}, &ctx).unwrap();
+        let ctx = test_ctx(MoveOrCopy::Move, &mp, &ctrlc);
+        let (msg, stats) =
+            merge_or_copy(&src_dir, &dest_dir, |_| {}, &ctx, &mut LinkMap::new()).unwrap();
 
         assert!(msg.contains("Renamed"));
         assert!(msg.contains("directory"));
@@ -507,15 +992,9 @@ mod tests {
 
         let mp = hidden_multi_progress();
         let ctrlc = noop_ctrlc();
-        let ctx = Ctx {
-            moc: MoveOrCopy::Move,
-            force: false,
-            dry_run: false,
-            batch_size: 1,
-            mp: &mp,
-            ctrlc: &ctrlc,
-        };
-        let (_, stats) = merge_or_copy(&src_dir, &dest_dir, |_| {}, &ctx).unwrap();
+        let ctx = test_ctx(MoveOrCopy::Move, &mp, &ctrlc);
+        let (_, stats) =
+            merge_or_copy(&src_dir, &dest_dir, |_| {}, &ctx, &mut LinkMap::new()).unwrap();
 
         // unique_src/ was renamed wholesale
         assert_eq!(stats.fast_path_dir_count, 1);
@@ -539,4 +1018,573 @@ mod tests {
         assert!(dest_dir.path().join("empty_dir").is_dir());
         assert!(src_dir.path().join("empty_dir").is_dir());
     }
+
+    #[test]
+    fn merge_directory_into_itself_fails() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "file1", "content");
+
+        for moc in [MoveOrCopy::Move, MoveOrCopy::Copy] {
+            assert_error_with_msg(
+                _merge_or_copy(&src_dir, &src_dir, moc, true),
+                "are the same directory",
+            );
+        }
+        assert_eq!(
+            fs::read_to_string(src_dir.path().join("file1")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn copy_fails_when_dest_subdir_is_symlink_to_source_subdir() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "subdir/file1", "content");
+
+        let dest_dir = tempdir().unwrap();
+        std::os::unix::fs::symlink(
+            src_dir.path().join("subdir"),
+            dest_dir.path().join("subdir"),
+        )
+        .unwrap();
+
+        assert_error_with_msg(
+            _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Copy, true),
+            "are the same directory",
+        );
+        assert_eq!(
+            fs::read_to_string(src_dir.path().join("subdir/file1")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn copy_directory_into_own_subdirectory_fails() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "file1", "content");
+        let dest_dir = src_dir.path().join("backup");
+
+        for moc in [MoveOrCopy::Move, MoveOrCopy::Copy] {
+            assert_error_with_msg(
+                _merge_or_copy(&src_dir, &dest_dir, moc, false),
+                "is inside source directory",
+            );
+        }
+        assert!(!dest_dir.exists());
+        assert!(src_dir.path().join("file1").exists());
+    }
+
+    #[test]
+    fn ensure_not_inside_resolves_dot_dot_and_symlinks() {
+        let work_dir = tempdir().unwrap();
+        let src = work_dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        std::os::unix::fs::symlink(&src, work_dir.path().join("alias")).unwrap();
+
+        assert!(ensure_not_inside(&src, &src.join("sub/../new")).is_err());
+        assert!(ensure_not_inside(&src, &work_dir.path().join("alias/new/deeper")).is_err());
+        assert!(ensure_not_inside(&src, &src.join("../sibling")).is_ok());
+        assert!(ensure_not_inside(&src, &src).is_ok());
+        assert!(ensure_not_inside(&src.join("sub"), &src).is_ok());
+    }
+
+    #[test]
+    fn find_conflicts_collects_every_kind() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "both_files", "src");
+        create_temp_file(src_dir.path(), "file_vs_dir", "src");
+        create_temp_file(src_dir.path(), "dir_vs_file/inner", "src");
+        create_temp_file(src_dir.path(), "nested/deep/both_files", "src");
+        create_temp_file(src_dir.path(), "src_only/file", "src");
+
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "both_files", "dest");
+        create_temp_file(dest_dir.path(), "file_vs_dir/inner", "dest");
+        create_temp_file(dest_dir.path(), "dir_vs_file", "dest");
+        create_temp_file(dest_dir.path(), "nested/deep/both_files", "dest");
+
+        let conflicts = find_conflicts(src_dir.path(), dest_dir.path(), false, Dereference::Never);
+        let names: Vec<_> = conflicts
+            .iter()
+            .map(|c| {
+                let (Conflict::FileOverFile(_, dest)
+                | Conflict::FileOverDir(_, dest)
+                | Conflict::DirOverFile(_, dest)
+                | Conflict::SameFile(_, dest)
+                | Conflict::SymlinkLoop(dest)
+                | Conflict::SameDest(_, _, dest)) = c;
+                dest.strip_prefix(dest_dir.path()).unwrap().to_path_buf()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "both_files",
+                "dir_vs_file",
+                "file_vs_dir",
+                "nested/deep/both_files"
+            ]
+            .map(PathBuf::from)
+        );
+        assert!(matches!(conflicts[1], Conflict::DirOverFile(..)));
+        assert!(matches!(conflicts[2], Conflict::FileOverDir(..)));
+
+        let conflicts = find_conflicts(src_dir.path(), dest_dir.path(), true, Dereference::Never);
+        assert_eq!(conflicts.len(), 2, "force only clears file-over-file");
+    }
+
+    #[test]
+    fn copy_preserves_directory_modes_including_sticky() {
+        use std::os::unix::fs::PermissionsExt;
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sticky/file", "content");
+        create_temp_file(src_dir.path(), "private/file", "content");
+        create_temp_file(src_dir.path(), "readonly/file", "content");
+        for (name, mode) in [("sticky", 0o1777), ("private", 0o700), ("readonly", 0o555)] {
+            fs::set_permissions(src_dir.path().join(name), fs::Permissions::from_mode(mode))
+                .unwrap();
+        }
+
+        let dest_dir = tempdir().unwrap();
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Copy, false).unwrap();
+
+        for (name, mode) in [("sticky", 0o1777), ("private", 0o700), ("readonly", 0o555)] {
+            let dest_mode = fs::metadata(dest_dir.path().join(name))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(dest_mode & 0o7777, mode, "mode of '{name}'");
+            assert!(dest_dir.path().join(name).join("file").exists());
+        }
+    }
+
+    #[test]
+    fn merge_keeps_mode_of_existing_dest_directory() {
+        use std::os::unix::fs::PermissionsExt;
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sub/file1", "content");
+        fs::set_permissions(
+            src_dir.path().join("sub"),
+            fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "sub/file2", "content");
+        fs::set_permissions(
+            dest_dir.path().join("sub"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Copy, false).unwrap();
+        let dest_mode = fs::metadata(dest_dir.path().join("sub"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(dest_mode & 0o7777, 0o755);
+    }
+
+    #[test]
+    fn copy_restores_directory_timestamps_after_populating() {
+        use std::os::unix::fs::MetadataExt;
+        use std::time::{Duration, SystemTime};
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sub/nested/file", "content");
+        for (name, secs) in [("sub/nested", 1_400_000_000), ("sub", 1_500_000_000)] {
+            let time = SystemTime::UNIX_EPOCH + Duration::new(secs, 987_654_321);
+            fs::File::open(src_dir.path().join(name))
+                .unwrap()
+                .set_times(fs::FileTimes::new().set_accessed(time).set_modified(time))
+                .unwrap();
+        }
+
+        let dest_dir = tempdir().unwrap();
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Copy, false).unwrap();
+
+        for (name, secs) in [("sub/nested", 1_400_000_000), ("sub", 1_500_000_000)] {
+            let dest_meta = fs::metadata(dest_dir.path().join(name)).unwrap();
+            assert_eq!(dest_meta.mtime(), secs, "mtime of '{name}'");
+            assert_eq!(dest_meta.mtime_nsec(), 987_654_321, "mtime of '{name}'");
+        }
+    }
+
+    #[test]
+    fn copy_as_root_preserves_ownership_of_files_and_directories() {
+        use std::os::unix::fs::{MetadataExt, chown};
+        skip_unless!(is_root(), "needs root to chown the source to someone else");
+        let src_dir = tempdir().unwrap();
+        let file = create_temp_file(src_dir.path(), "sub/file", "content");
+        chown(&file, Some(54321), Some(54322)).unwrap();
+        chown(src_dir.path().join("sub"), Some(54323), Some(54324)).unwrap();
+
+        let dest_dir = tempdir().unwrap();
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Copy, false).unwrap();
+
+        let owner = |p: &str| {
+            let m = fs::metadata(dest_dir.path().join(p)).unwrap();
+            (m.uid(), m.gid())
+        };
+        assert_eq!(owner("sub/file"), (54321, 54322));
+        assert_eq!(owner("sub"), (54323, 54324));
+    }
+
+    #[test]
+    fn copy_recreates_symlinks_instead_of_following_them() {
+        let outside = tempdir().unwrap();
+        create_temp_file(outside.path(), "secret", "outside");
+
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "file1", "content");
+        std::os::unix::fs::symlink(outside.path(), src_dir.path().join("to_dir")).unwrap();
+        std::os::unix::fs::symlink("file1", src_dir.path().join("to_file")).unwrap();
+
+        let dest_dir = tempdir().unwrap();
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Copy, false).unwrap();
+
+        let to_dir = dest_dir.path().join("to_dir");
+        assert!(fs::symlink_metadata(&to_dir).unwrap().is_symlink());
+        assert_eq!(fs::read_link(&to_dir).unwrap(), outside.path());
+        assert_eq!(
+            fs::read_link(dest_dir.path().join("to_file")).unwrap(),
+            PathBuf::from("file1")
+        );
+    }
+
+    #[test]
+    fn move_leaves_symlinked_directory_contents_in_place() {
+        let outside = tempdir().unwrap();
+        create_temp_file(outside.path(), "keep_me", "outside");
+
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sub/file1", "content");
+        std::os::unix::fs::symlink(outside.path(), src_dir.path().join("sub/link")).unwrap();
+
+        // Existing dest/sub forces an entry-by-entry merge
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "sub/other", "dest");
+
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Move, false).unwrap();
+
+        assert!(outside.path().join("keep_me").exists());
+        let link = dest_dir.path().join("sub/link");
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), outside.path());
+        assert!(!src_dir.path().exists());
+    }
+
+    #[test]
+    fn collect_total_size_does_not_follow_symlinks() {
+        let outside = tempdir().unwrap();
+        create_temp_file(outside.path(), "big", "0123456789");
+
+        let temp_dir = tempdir().unwrap();
+        create_temp_file(temp_dir.path(), "file1", "abc");
+        std::os::unix::fs::symlink(outside.path(), temp_dir.path().join("to_dir")).unwrap();
+        std::os::unix::fs::symlink(outside.path().join("big"), temp_dir.path().join("to_file"))
+            .unwrap();
+        assert_eq!(collect_total_size(temp_dir.path(), false), 3);
+    }
+
+    #[test]
+    fn dereference_always_copies_link_targets() {
+        let outside = tempdir().unwrap();
+        create_temp_file(outside.path(), "secret", "outside");
+
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "file1", "content");
+        std::os::unix::fs::symlink(outside.path(), src_dir.path().join("to_dir")).unwrap();
+        std::os::unix::fs::symlink("file1", src_dir.path().join("to_file")).unwrap();
+
+        let dest_dir = tempdir().unwrap();
+        _merge_or_copy_deref(&src_dir, &dest_dir, MoveOrCopy::Copy, Dereference::Always).unwrap();
+
+        let to_dir = dest_dir.path().join("to_dir");
+        assert!(fs::symlink_metadata(&to_dir).unwrap().is_dir());
+        assert_eq!(
+            fs::read_to_string(to_dir.join("secret")).unwrap(),
+            "outside"
+        );
+        let to_file = dest_dir.path().join("to_file");
+        assert!(fs::symlink_metadata(&to_file).unwrap().is_file());
+        assert_eq!(fs::read_to_string(&to_file).unwrap(), "content");
+    }
+
+    #[test]
+    fn dereference_command_line_follows_only_top_level_link() {
+        let work_dir = tempdir().unwrap();
+        let real = work_dir.path().join("real");
+        create_temp_file(&real, "file1", "content");
+        std::os::unix::fs::symlink("file1", real.join("inner_link")).unwrap();
+        let top_link = work_dir.path().join("top_link");
+        std::os::unix::fs::symlink(&real, &top_link).unwrap();
+
+        let dest = work_dir.path().join("dest");
+        _merge_or_copy_deref(&top_link, &dest, MoveOrCopy::Copy, Dereference::CommandLine).unwrap();
+
+        assert!(fs::symlink_metadata(&dest).unwrap().is_dir());
+        assert_eq!(
+            fs::read_link(dest.join("inner_link")).unwrap(),
+            PathBuf::from("file1")
+        );
+    }
+
+    #[test]
+    fn dereference_errors_on_symlink_loop() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sub/file1", "content");
+        std::os::unix::fs::symlink("..", src_dir.path().join("sub/up")).unwrap();
+
+        let dest_dir = tempdir().unwrap();
+        assert_error_with_msg(
+            _merge_or_copy_deref(&src_dir, &dest_dir, MoveOrCopy::Copy, Dereference::Always),
+            "Symlink loop",
+        );
+        assert_eq!(collect_total_size(src_dir.path(), true), 7);
+    }
+
+    #[test]
+    fn move_with_dereference_keeps_link_target() {
+        let outside = tempdir().unwrap();
+        create_temp_file(outside.path(), "keep_me", "outside");
+
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "file1", "content");
+        std::os::unix::fs::symlink(outside.path(), src_dir.path().join("to_dir")).unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("keep_me"),
+            src_dir.path().join("to_file"),
+        )
+        .unwrap();
+
+        let dest = tempdir().unwrap();
+        let dest = dest.path().join("dest");
+        _merge_or_copy_deref(&src_dir, &dest, MoveOrCopy::Move, Dereference::Always).unwrap();
+
+        assert!(outside.path().join("keep_me").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("to_dir/keep_me")).unwrap(),
+            "outside"
+        );
+        assert_eq!(fs::read_to_string(dest.join("to_file")).unwrap(), "outside");
+        assert!(!src_dir.path().exists());
+    }
+
+    #[test]
+    fn copy_preserves_hard_links() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "a/file1", "content");
+        fs::create_dir(src_dir.path().join("b")).unwrap();
+        fs::hard_link(
+            src_dir.path().join("a/file1"),
+            src_dir.path().join("b/file2"),
+        )
+        .unwrap();
+        assert_eq!(collect_total_size(src_dir.path(), false), 7);
+
+        let dest_dir = tempdir().unwrap();
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Copy, false).unwrap();
+
+        let ino = |p: &str| fs::metadata(dest_dir.path().join(p)).unwrap().ino();
+        assert_eq!(ino("a/file1"), ino("b/file2"));
+        assert_ne!(
+            ino("a/file1"),
+            fs::metadata(src_dir.path().join("a/file1")).unwrap().ino()
+        );
+    }
+
+    #[test]
+    fn merge_move_preserves_hard_links() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sub/file1", "content");
+        fs::hard_link(
+            src_dir.path().join("sub/file1"),
+            src_dir.path().join("sub/file2"),
+        )
+        .unwrap();
+
+        // Existing dest/sub forces an entry-by-entry merge
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "sub/other", "dest");
+
+        _merge_or_copy(&src_dir, &dest_dir, MoveOrCopy::Move, false).unwrap();
+
+        let meta = |p: &str| fs::metadata(dest_dir.path().join(p)).unwrap();
+        assert_eq!(meta("sub/file1").ino(), meta("sub/file2").ino());
+        assert_eq!(meta("sub/file1").nlink(), 2);
+        assert!(!src_dir.path().exists());
+    }
+
+    fn _link_tree(src: &Path, dest: &Path, link: LinkMode) -> anyhow::Result<String> {
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            link,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        merge_or_copy(src, dest, |_| {}, &ctx, &mut LinkMap::new()).map(|(msg, _)| msg)
+    }
+
+    #[test]
+    fn link_mode_hard_links_files_into_merged_tree() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sub/file1", "content");
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "sub/other", "dest");
+
+        _link_tree(
+            src_dir.path(),
+            dest_dir.path(),
+            LinkMode::Hard {
+                copy_fallback: false,
+            },
+        )
+        .unwrap();
+
+        let ino = |p: &Path| fs::metadata(p).unwrap().ino();
+        assert_eq!(
+            ino(&src_dir.path().join("sub/file1")),
+            ino(&dest_dir.path().join("sub/file1"))
+        );
+        assert!(dest_dir.path().join("sub/other").exists());
+    }
+
+    #[test]
+    fn link_mode_symlinks_files() {
+        let work_dir = tempdir().unwrap();
+        let src = work_dir.path().join("src");
+        create_temp_file(&src, "sub/file1", "content");
+
+        let abs = work_dir.path().join("abs");
+        _link_tree(&src, &abs, LinkMode::Symbolic { relative: false }).unwrap();
+        let target = fs::read_link(abs.join("sub/file1")).unwrap();
+        assert!(target.is_absolute());
+        assert!(fs::symlink_metadata(abs.join("sub")).unwrap().is_dir());
+
+        let rel = work_dir.path().join("rel");
+        _link_tree(&src, &rel, LinkMode::Symbolic { relative: true }).unwrap();
+        assert_eq!(
+            fs::read_link(rel.join("sub/file1")).unwrap(),
+            PathBuf::from("../../src/sub/file1")
+        );
+        assert_eq!(fs::read_dir(rel.join("sub")).unwrap().count(), 1);
+        assert_eq!(
+            fs::read_to_string(rel.join("sub/file1")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn parallel_copy_transfers_every_file_and_tracks_progress() {
+        let src_dir = tempdir().unwrap();
+        for i in 0..50 {
+            create_temp_file(src_dir.path(), &format!("sub/file{i:02}"), &"x".repeat(i));
+        }
+        fs::hard_link(
+            src_dir.path().join("sub/file10"),
+            src_dir.path().join("sub/linked"),
+        )
+        .unwrap();
+        let total = collect_total_size(src_dir.path(), false);
+        let dest_dir = tempdir().unwrap();
+
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            jobs: 4,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let max_pos = AtomicU64::new(0);
+        let (_, stats) = merge_or_copy(
+            src_dir.path(),
+            dest_dir.path(),
+            |pos| {
+                max_pos.fetch_max(pos, Ordering::Relaxed);
+            },
+            &ctx,
+            &mut LinkMap::new(),
+        )
+        .unwrap();
+
+        for i in 0..50 {
+            assert_file_copied(
+                src_dir.path().join(format!("sub/file{i:02}")),
+                dest_dir.path().join(format!("sub/file{i:02}")),
+            );
+        }
+        let ino = |p: &str| fs::metadata(dest_dir.path().join(p)).unwrap().ino();
+        assert_eq!(ino("sub/file10"), ino("sub/linked"));
+        assert_eq!(stats.io_bytes, total);
+        assert_eq!(max_pos.load(Ordering::Relaxed), total);
+    }
+
+    #[test]
+    fn parallel_results_follow_directory_order() {
+        let src_dir = tempdir().unwrap();
+        let dest_dir = tempdir().unwrap();
+        // Earlier files are bigger, so later ones tend to finish first.
+        let entries: Vec<PathBuf> = (0..40)
+            .map(|i| {
+                create_temp_file(
+                    src_dir.path(),
+                    &format!("file{i:02}"),
+                    &"x".repeat((40 - i) << 14),
+                )
+            })
+            .collect();
+        let pending: Vec<_> = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (i, dest_dir.path().join(entry.file_name().unwrap())))
+            .collect();
+
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            jobs: 4,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let pb = indicatif::ProgressBar::hidden();
+        let results = transfer_files_parallel(&entries, &pending, false, &ctx, &pb, &|_| {});
+
+        assert_eq!(results.len(), 40);
+        for (i, result) in results.into_iter().enumerate() {
+            let (msg, _) = result.unwrap().unwrap();
+            assert!(msg.contains(&format!("file{i:02}")), "{i}: {msg}");
+        }
+    }
+
+    #[test]
+    fn parallel_merge_move() {
+        let src_dir = tempdir().unwrap();
+        for i in 0..20 {
+            create_temp_file(src_dir.path(), &format!("sub/file{i}"), "content");
+        }
+        // Existing dest/sub forces an entry-by-entry merge
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "sub/other", "dest");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            jobs: 3,
+            ..test_ctx(MoveOrCopy::Move, &mp, &ctrlc)
+        };
+        merge_or_copy(
+            src_dir.path(),
+            dest_dir.path(),
+            |_| {},
+            &ctx,
+            &mut LinkMap::new(),
+        )
+        .unwrap();
+
+        for i in 0..20 {
+            assert_eq!(
+                fs::read_to_string(dest_dir.path().join(format!("sub/file{i}"))).unwrap(),
+                "content"
+            );
+        }
+        assert!(!src_dir.path().exists());
+    }
 }
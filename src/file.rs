@@ -1,15 +1,30 @@
-use crate::{Ctx, MoveOrCopy, SourceKind, TransferStats, item_progress_bar, message_with_arrow};
-use anyhow::{bail, ensure};
+use crate::{
+    Cancelled, Ctx, FAIL_MARK, LinkMode, MoveOrCopy, ReflinkMode, SourceKind, SparseMode,
+    TransferStats, dir::canonicalize_lossy, item_progress_bar, message_with_arrow, meta, metadata,
+};
+use anyhow::{Context, bail, ensure};
+use colored::Colorize;
 use std::{
+    ffi::CString,
     fs,
-    io::{Read, Write},
-    path::{Path, PathBuf},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Component, Path, PathBuf},
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
 };
 
 pub(crate) fn move_or_copy<Src: AsRef<Path>, Dest: AsRef<Path>, F: Fn(u64)>(
     src: Src,
     dest: Dest,
     progress_cb: F,
+    follow: bool,
     ctx: &Ctx,
 ) -> anyhow::Result<(String, TransferStats)> {
     let src = src.as_ref();
@@ -20,407 +35,2574 @@ pub(crate) fn move_or_copy<Src: AsRef<Path>, Dest: AsRef<Path>, F: Fn(u64)>(
         ctx.moc,
         ctx.force,
     );
-    let dest = ensure_dest(src, &dest, ctx.force)?;
+    let dest = ensure_dest(src, &dest, follow, ctx.force)?;
+    let src_meta = metadata(src, follow)?;
+    // A followed symlink supplies the data and metadata of its target, but is
+    // copied rather than renamed even when moving, and only the link is removed.
+    let via_link = !src_meta.is_symlink() && fs::symlink_metadata(src)?.is_symlink();
+    let data_src = if via_link {
+        fs::canonicalize(src)?
+    } else {
+        src.to_path_buf()
+    };
+    let mut fast_moc = if via_link { MoveOrCopy::Copy } else { ctx.moc };
 
     let timer = std::time::Instant::now();
     if let Some(dest_parent) = dest.parent() {
-        fs::create_dir_all(dest_parent)?;
+        create_dirs(dest_parent, ctx.sync)?;
     }
 
-    let result = match ctx.moc {
-        MoveOrCopy::Move => fs::rename(src, &dest),
-        MoveOrCopy::Copy => {
-            if dest.exists() {
-                fs::remove_file(&dest)?;
+    match ctx.link {
+        LinkMode::Off => {}
+        LinkMode::Hard { copy_fallback } => {
+            match create_in_place(&dest, ctx.sync, |path| fs::hard_link(&data_src, path)) {
+                Ok(()) => {
+                    return Ok((
+                        labeled_message("Hard-linked", src, &dest, ctx),
+                        TransferStats::default(),
+                    ));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices && copy_fallback => {
+                    log::debug!(
+                        "Can't hard-link '{}' across devices, copying instead.",
+                        src.display()
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => bail!(
+                    "Can't hard-link '{}' to '{}' across devices (use --link-fallback to copy instead)",
+                    src.display(),
+                    dest.display()
+                ),
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("hard-linking '{}' to '{}'", src.display(), dest.display())
+                    });
+                }
             }
-            reflink::reflink(src, &dest)
         }
+        LinkMode::Symbolic { relative } => {
+            let target = symlink_target(src, &dest, relative);
+            create_in_place(&dest, ctx.sync, |path| {
+                std::os::unix::fs::symlink(&target, path)
+            })?;
+            return Ok((
+                labeled_message("Symlinked", src, &dest, ctx),
+                TransferStats::default(),
+            ));
+        }
+    }
+
+    if src_meta.is_symlink() && matches!(ctx.moc, MoveOrCopy::Copy) {
+        return copy_symlink(src, &src_meta, &dest, timer, ctx);
+    }
+    if is_special(&src_meta) && matches!(fast_moc, MoveOrCopy::Copy) {
+        return copy_special(src, &data_src, &src_meta, &dest, timer, ctx);
+    }
+
+    // Copied data is written under a temporary name and renamed over `dest`
+    // only once complete, so `dest` never holds a partial file.
+    let part = PartFile::new(&dest, ctx.resume);
+    // Where an interrupted copy left off. Cloning would replace what's there.
+    let resume = if ctx.resume && src_meta.is_file() {
+        part.resume_point(&data_src, ctx.force)
+    } else {
+        None
+    };
+    // `None` when the fast path is ruled out by `--reflink=never`.
+    let mut result = match fast_moc {
+        MoveOrCopy::Move => Some(fs::rename(src, &dest)),
+        MoveOrCopy::Copy if ctx.reflink == ReflinkMode::Never || resume.is_some() => None,
+        MoveOrCopy::Copy => Some(part.reflink_from(&data_src)),
     };
+    // Renaming across btrfs subvolumes fails with EXDEV even though both are
+    // on one filesystem, where cloning and then unlinking is still instant.
+    if matches!(fast_moc, MoveOrCopy::Move)
+        && ctx.reflink != ReflinkMode::Never
+        && resume.is_none()
+        && src_meta.is_file()
+        && result.as_ref().is_some_and(|r| {
+            r.as_ref()
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::CrossesDevices)
+        })
+    {
+        log::debug!(
+            "Renaming '{}' crosses devices, trying reflink.",
+            src.display()
+        );
+        fast_moc = MoveOrCopy::Copy;
+        result = Some(part.reflink_from(&data_src));
+    }
     let fallback = match ctx.moc {
         MoveOrCopy::Move => "copy and delete",
         MoveOrCopy::Copy => "copy",
     };
     match result {
-        Ok(()) => {
-            let detail = format!(
-                "{}: {}",
-                match ctx.moc {
-                    MoveOrCopy::Move => "Renamed",
-                    MoveOrCopy::Copy => "Reflinked",
-                },
-                message_with_arrow(src, dest, ctx.moc, true)
-            );
+        None => log::debug!("Reflink disabled, falling back to {fallback}."),
+        Some(Ok(())) => {
+            if matches!(fast_moc, MoveOrCopy::Copy) {
+                meta::copy_metadata(&data_src, &src_meta, part.path(), ctx.skip_xattrs)?;
+                part.persist(ctx.sync)?;
+            }
+            if matches!((ctx.moc, fast_moc), (MoveOrCopy::Move, MoveOrCopy::Copy)) {
+                fs::remove_file(src)?;
+            }
+            let label = match fast_moc {
+                MoveOrCopy::Move => "Renamed",
+                MoveOrCopy::Copy => "Reflinked",
+            };
             let stats = TransferStats {
                 io_bytes: 0,
+                apparent_bytes: 0,
+                kernel_bytes: 0,
                 fast_path_file_count: 1,
+                reflinked_file_count: u64::from(matches!(fast_moc, MoveOrCopy::Copy)),
                 fast_path_dir_count: 0,
             };
-            return Ok((
+            return Ok((labeled_message(label, src, &dest, ctx), stats));
+        }
+        Some(Err(e))
+            if matches!(fast_moc, MoveOrCopy::Copy) && ctx.reflink == ReflinkMode::Always =>
+        {
+            return Err(e).with_context(|| {
                 format!(
-                    "{} {}",
-                    SourceKind::File.done_arrow(),
-                    ctx.maybe_dim(detail)
-                ),
-                stats,
-            ));
+                    "cloning '{}' to '{}' (--reflink=always)",
+                    src.display(),
+                    dest.display()
+                )
+            });
         }
-        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+        Some(Err(e)) if e.kind() == std::io::ErrorKind::CrossesDevices => {
             log::debug!(
                 "'{}' and '{}' are on different devices, falling back to {fallback}.",
                 src.display(),
                 dest.display()
             );
         }
-        Err(e) if e.raw_os_error().is_some_and(|e| e == libc::ENOTSUP) => {
+        Some(Err(e)) if e.raw_os_error().is_some_and(|e| e == libc::ENOTSUP) => {
             log::debug!("Operation not supported, falling back to {fallback}. Full error: {e:?}");
         }
-        Err(e) => bail!(e),
+        Some(Err(e)) => bail!(e),
+    }
+
+    if src_meta.is_symlink() {
+        return copy_symlink(src, &src_meta, &dest, timer, ctx);
+    }
+    if is_special(&src_meta) {
+        return copy_special(src, &data_src, &src_meta, &dest, timer, ctx);
     }
 
-    let file_size = fs::metadata(src)?.len();
+    let file_size = src_meta.len();
     let pb_bytes = ctx
         .mp
         .add(item_progress_bar(file_size, src, &dest, ctx.moc));
 
-    buffered_copy(src, &dest, &pb_bytes, &progress_cb)?;
+    let offset = match resume {
+        Some((offset, from)) => {
+            log::debug!("Resuming '{}' at {offset} bytes", src.display());
+            if from != part.path() {
+                fs::rename(&from, part.path())?;
+            }
+            offset
+        }
+        None => 0,
+    };
+    let stats = buffered_copy(&data_src, &part, offset, ctx, &pb_bytes, &progress_cb)?;
+    meta::copy_metadata(&data_src, &src_meta, part.path(), ctx.skip_xattrs)?;
+    // Also replaces a symlink at `dest` rather than writing through it.
+    part.persist(ctx.sync)?;
 
     if matches!(ctx.moc, MoveOrCopy::Move) {
         fs::remove_file(src)?;
     }
     pb_bytes.finish_and_clear();
 
-    let stats = TransferStats {
-        io_bytes: file_size,
-        fast_path_file_count: 0,
-        fast_path_dir_count: 0,
-    };
     Ok((
         ctx.done_message(SourceKind::File, stats, timer.elapsed(), src, dest),
         stats,
     ))
 }
 
-fn buffered_copy<F: Fn(u64)>(
+/// Recreate symlink `src` at `dest` with the exact same link text, rather
+/// than copying whatever it points to. Removes `src` when moving.
+fn copy_symlink(
     src: &Path,
+    src_meta: &fs::Metadata,
     dest: &Path,
-    pb: &indicatif::ProgressBar,
-    progress_cb: F,
-) -> anyhow::Result<()> {
-    let mut reader = fs::File::open(src)?;
-    let mut writer = fs::File::create(dest)?;
-    let mut buf = vec![0u8; 1024 * 1024];
-    let mut copied = 0u64;
-    loop {
-        let n = reader.read(&mut buf)?;
-        if n == 0 {
-            break;
-        }
-        writer.write_all(&buf[..n])?;
-        copied += n as u64;
-        pb.set_position(copied);
-        progress_cb(copied);
+    timer: std::time::Instant,
+    ctx: &Ctx,
+) -> anyhow::Result<(String, TransferStats)> {
+    let target = fs::read_link(src)?;
+    create_in_place(dest, ctx.sync, |path| {
+        std::os::unix::fs::symlink(&target, path)?;
+        meta::copy_symlink_metadata(src_meta, path)
+    })?;
+    if matches!(ctx.moc, MoveOrCopy::Move) {
+        fs::remove_file(src)?;
     }
-    Ok(())
+
+    let stats = TransferStats::default();
+    Ok((
+        ctx.done_message(SourceKind::File, stats, timer.elapsed(), src, dest),
+        stats,
+    ))
 }
 
-fn ensure_dest<Src: AsRef<Path>, Dest: AsRef<Path>>(
-    src: Src,
-    dest: Dest,
-    force: bool,
-) -> anyhow::Result<PathBuf> {
-    let src = src.as_ref();
-    let mut dest = dest.as_ref().to_path_buf();
-    ensure!(src.exists(), "Source '{}' does not exist", src.display());
-    ensure!(
-        src.is_file(),
-        "Source '{}' exists but is not a file",
-        src.display()
-    );
+fn is_special(meta: &fs::Metadata) -> bool {
+    let ft = meta.file_type();
+    ft.is_fifo() || ft.is_char_device() || ft.is_block_device() || ft.is_socket()
+}
 
-    if dest.is_dir() || (!dest.exists() && dest.to_string_lossy().ends_with('/')) {
-        match src.file_name() {
-            Some(name) => dest.push(name),
-            None => bail!("Cannot get file name from '{}'", src.display()),
-        }
-    }
-    if dest.exists() {
-        ensure!(
-            dest.is_file(),
-            "Destination '{}' already exists and is not a file",
-            dest.display()
-        );
-        ensure!(
-            force,
-            "Destination '{}' already exists (use -f to overwrite)",
-            dest.display()
+/// Recreate FIFO or device node `src` at `dest` rather than reading from it,
+/// taking metadata from `data_src`. Sockets can't be recreated and device
+/// nodes need privileges, so those are skipped with a warning and `src` is
+/// left in place. Otherwise removes `src` when moving.
+fn copy_special(
+    src: &Path,
+    data_src: &Path,
+    src_meta: &fs::Metadata,
+    dest: &Path,
+    timer: std::time::Instant,
+    ctx: &Ctx,
+) -> anyhow::Result<(String, TransferStats)> {
+    let ft = src_meta.file_type();
+    let skipped = |what: &str| {
+        log::warn!("Skipping {what} '{}'", src.display());
+        let detail = format!(
+            "Skipped {what}: {}",
+            message_with_arrow(src, dest, ctx.moc, true)
         );
+        Ok((
+            format!("{} {}", FAIL_MARK.yellow().bold(), ctx.maybe_dim(detail)),
+            TransferStats::default(),
+        ))
+    };
+    if ft.is_socket() {
+        return skipped("socket");
     }
-    Ok(dest)
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::tests::{
-        assert_error_with_msg, assert_file_copied, assert_file_moved, assert_file_not_moved,
-        create_temp_file, hidden_multi_progress,
+    // Created under the part name, so a failure leaves `dest` as it was.
+    let part = PartFile::new(dest, false);
+    remove_existing(part.path())?;
+    let path = CString::new(part.path().as_os_str().as_bytes())?;
+    // Owner-only until the source mode is applied.
+    let ret = if ft.is_fifo() {
+        unsafe { libc::mkfifo(path.as_ptr(), 0o600) }
+    } else {
+        let kind = src_meta.mode() as libc::mode_t & libc::S_IFMT;
+        unsafe { libc::mknod(path.as_ptr(), kind | 0o600, src_meta.rdev() as libc::dev_t) }
     };
-    use serial_test::serial;
-    use std::fs;
-    use std::sync::atomic::AtomicBool;
-    use tempfile::tempdir;
-
-    fn move_file<Src: AsRef<Path>, Dest: AsRef<Path>>(
-        src: Src,
-        dest: Dest,
-        force: bool,
-    ) -> anyhow::Result<String> {
-        let mp = hidden_multi_progress();
-        let ctrlc = AtomicBool::new(false);
-        let ctx = Ctx {
-            moc: MoveOrCopy::Move,
-            force,
-            dry_run: false,
-            batch_size: 1,
-            mp: &mp,
-            ctrlc: &ctrlc,
-        };
-        move_or_copy(src, dest, |_| {}, &ctx).map(|(msg, _)| msg)
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EPERM) {
+            return skipped("device node (requires root)");
+        }
+        return Err(err).with_context(|| format!("creating '{}'", dest.display()));
     }
-
-    fn copy_file<Src: AsRef<Path>, Dest: AsRef<Path>>(
-        src: Src,
-        dest: Dest,
-        force: bool,
-    ) -> anyhow::Result<String> {
-        let mp = hidden_multi_progress();
-        let ctrlc = AtomicBool::new(false);
-        let ctx = Ctx {
-            moc: MoveOrCopy::Copy,
-            force,
-            dry_run: false,
-            batch_size: 1,
-            mp: &mp,
-            ctrlc: &ctrlc,
-        };
-        move_or_copy(src, dest, |_| {}, &ctx).map(|(msg, _)| msg)
+    meta::copy_metadata(data_src, src_meta, part.path(), ctx.skip_xattrs)?;
+    // Not `persist(ctx.sync)`: opening a fifo or device node to flush it
+    // could block or have side effects.
+    part.persist(false)?;
+    if ctx.sync {
+        sync_parent(dest)?;
+    }
+    if matches!(ctx.moc, MoveOrCopy::Move) {
+        fs::remove_file(src)?;
     }
 
-    #[test]
-    fn move_file_succeeds_with_absolute_path() {
-        let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_path = work_dir.path().join("b");
+    let stats = TransferStats::default();
+    Ok((
+        ctx.done_message(SourceKind::File, stats, timer.elapsed(), src, dest),
+        stats,
+    ))
+}
 
-        move_file(&src_path, &dest_path, false).unwrap();
-        assert_file_moved(&src_path, &dest_path, src_content);
+/// Hard-link `dest` to `first`, the already transferred copy of another link
+/// to the same inode as `src`, instead of copying the data again. Removes
+/// `src` when moving.
+pub(crate) fn link_to_copy(
+    src: &Path,
+    first: &Path,
+    dest: &Path,
+    follow: bool,
+    ctx: &Ctx,
+) -> anyhow::Result<(String, TransferStats)> {
+    let dest = ensure_dest(src, dest, follow, ctx.force)?;
+    create_in_place(&dest, ctx.sync, |path| fs::hard_link(first, path))?;
+    if matches!(ctx.moc, MoveOrCopy::Move) {
+        fs::remove_file(src)?;
     }
+    Ok((
+        labeled_message("Hard-linked", src, &dest, ctx),
+        TransferStats::default(),
+    ))
+}
 
-    #[test]
-    fn copy_file_succeeds_with_absolute_path() {
-        let work_dir = tempdir().unwrap();
-        let src_path = create_temp_file(work_dir.path(), "a", "This is a test file");
-        let dest_path = work_dir.path().join("b");
-
-        copy_file(&src_path, &dest_path, false).unwrap();
-        assert_file_copied(&src_path, &dest_path);
+/// Path for a symlink at `dest` pointing to `src`: absolute, or relative to
+/// the directory holding `dest`. Resolves symlinks in both parent
+/// directories, but not `src` itself.
+fn symlink_target(src: &Path, dest: &Path, relative: bool) -> PathBuf {
+    let canonical = |path: &Path| {
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+        canonicalize_lossy(parent.unwrap_or(Path::new(".")))
+    };
+    let target = canonical(src).join(src.file_name().unwrap_or_default());
+    if !relative {
+        return target;
     }
+    let from = canonical(dest);
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = target.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    std::iter::repeat_n(Component::ParentDir, from.len() - common)
+        .chain(to[common..].iter().copied())
+        .collect()
+}
 
-    #[test]
-    #[serial]
-    fn move_file_succeeds_with_relative_path() {
-        let work_dir = tempdir().unwrap();
-        std::env::set_current_dir(&work_dir).unwrap();
-        let src_content = "This is a test file";
-        fs::write("a", src_content).unwrap();
+/// Completion message for a file handled without copying data, e.g.
+/// "Reflinked: a => b".
+fn labeled_message(label: &str, src: &Path, dest: &Path, ctx: &Ctx) -> String {
+    let detail = format!("{label}: {}", message_with_arrow(src, dest, ctx.moc, true));
+    format!(
+        "{} {}",
+        SourceKind::File.done_arrow(),
+        ctx.maybe_dim(detail)
+    )
+}
 
-        move_file("a", "b", false).unwrap();
-        assert_file_moved("a", "b", src_content);
-    }
+/// Flush file or directory `path` to disk.
+pub(crate) fn sync_path(path: &Path) -> std::io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
 
-    #[test]
-    #[serial]
-    fn copy_file_succeeds_with_relative_path() {
-        let work_dir = tempdir().unwrap();
-        std::env::set_current_dir(&work_dir).unwrap();
-        let src_content = "This is a test file";
-        fs::write("a", src_content).unwrap();
+/// Flush the directory holding `path`, making its entry durable.
+pub(crate) fn sync_parent(path: &Path) -> std::io::Result<()> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+    sync_path(parent.unwrap_or(Path::new(".")))
+}
 
-        copy_file("a", "b", false).unwrap();
-        assert_file_copied("a", "b");
+/// Create `dir` along with any missing parents. With `sync`, flush every
+/// directory created and the existing one holding them, so none of them can
+/// vanish in a crash and take synced files inside along with it.
+pub(crate) fn create_dirs(dir: &Path, sync: bool) -> std::io::Result<()> {
+    // Innermost first.
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+        .collect();
+    fs::create_dir_all(dir)?;
+    if sync && let Some(outermost) = missing.last() {
+        for path in &missing {
+            sync_path(path)?;
+        }
+        sync_parent(outermost)?;
     }
+    Ok(())
+}
 
-    #[test]
-    fn move_file_overwrites_existing_dest_with_force() {
-        let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+/// Remove whatever is at `dest` (including a dangling symlink), if anything.
+fn remove_existing(dest: &Path) -> std::io::Result<()> {
+    match fs::remove_file(dest) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
 
-        move_file(&src_path, &dest_path, true).unwrap();
-        assert_file_moved(&src_path, &dest_path, src_content);
+/// Create an entry with `create` under the part name and rename it over
+/// `dest`, so a failure leaves whatever was at `dest` in place.
+fn create_in_place(
+    dest: &Path,
+    sync: bool,
+    create: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let part = PartFile::new(dest, false);
+    remove_existing(part.path())?;
+    create(part.path())?;
+    // Only the entry needs flushing; opening a symlink would follow it.
+    part.persist(false)?;
+    if sync {
+        sync_parent(dest)?;
     }
+    Ok(())
+}
 
-    #[test]
-    fn copy_file_overwrites_existing_dest_with_force() {
-        let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+/// Part files currently being written, for `remove_part_files`.
+static PART_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
 
-        copy_file(&src_path, &dest_path, true).unwrap();
-        assert_file_copied(&src_path, &dest_path);
+/// Remove the part files of all unfinished copies, for a forced exit that
+/// skips their destructors.
+pub(crate) fn remove_part_files() {
+    for path in PART_FILES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        let _ = fs::remove_file(path);
     }
+}
 
-    #[test]
-    fn move_file_fails_without_force_when_dest_exists() {
-        let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+/// Longest file name Linux and macOS filesystems accept, in bytes.
+const NAME_MAX: usize = 255;
 
-        assert_error_with_msg(
-            move_file(&src_path, &dest_path, false),
-            "already exists (use -f to overwrite)",
-        );
+/// Hidden `.<name>.mvx-part` file next to `dest` that a copy is written to
+/// before being renamed into place. Removed on drop unless persisted, so a
+/// failed or cancelled copy leaves `dest` as it was, or kept with `keep` so
+/// `--resume` can pick it up again.
+struct PartFile {
+    path: PathBuf,
+    dest: PathBuf,
+    keep: std::cell::Cell<bool>,
+    persisted: bool,
+}
+
+impl PartFile {
+    fn new(dest: &Path, keep: bool) -> Self {
+        let path = dest.with_file_name(Self::name(dest.file_name().unwrap_or_default()));
+        let part = Self {
+            path,
+            dest: dest.to_path_buf(),
+            keep: std::cell::Cell::new(true),
+            persisted: false,
+        };
+        if !keep {
+            part.remove_on_failure();
+        }
+        part
     }
 
-    #[test]
-    fn copy_file_fails_without_force_when_dest_exists() {
-        let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+    /// `.<name>.mvx-part`, or for a name too long to take the extra bytes,
+    /// a shortened name with a hash of the full one, so it's still the same
+    /// name each run for `--resume` to find.
+    fn name(name: &std::ffi::OsStr) -> std::ffi::OsString {
+        const SUFFIX: &str = ".mvx-part";
+        let bytes = name.as_bytes();
+        let mut part = std::ffi::OsString::from(".");
+        if 1 + bytes.len() + SUFFIX.len() <= NAME_MAX {
+            part.push(name);
+        } else {
+            // FNV-1a, which unlike `DefaultHasher` is the same in every build.
+            let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| {
+                (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+            });
+            let tag = format!("~{hash:016x}");
+            let mut keep = NAME_MAX - 1 - tag.len() - SUFFIX.len();
+            // Don't split a UTF-8 character.
+            while bytes[keep] & 0xc0 == 0x80 {
+                keep -= 1;
+            }
+            part.push(std::ffi::OsStr::from_bytes(&bytes[..keep]));
+            part.push(tag);
+        }
+        part.push(SUFFIX);
+        part
+    }
 
-        assert_error_with_msg(
-            copy_file(&src_path, &dest_path, false),
-            "already exists (use -f to overwrite)",
-        );
+    fn path(&self) -> &Path {
+        &self.path
     }
 
-    #[test]
-    fn move_file_fails_with_nonexistent_source() {
-        let work_dir = tempdir().unwrap();
-        let src_path = work_dir.path().join("a");
-        let dest_content = "This is a test file";
-        let dest_path = create_temp_file(work_dir.path(), "b", dest_content);
+    /// Don't keep the part file for `--resume` after all, for a copy whose
+    /// length no longer tells how much of it is done.
+    fn remove_on_failure(&self) {
+        if self.keep.replace(false) {
+            PART_FILES
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(self.path.clone());
+        }
+    }
 
-        assert!(!src_path.exists(), "Source file should not exist initially");
-        assert_error_with_msg(
-            move_file(&src_path, "/dest/does/not/matter", false),
-            "does not exist",
-        );
-        assert_eq!(
-            fs::read_to_string(dest_path).unwrap(),
-            dest_content,
-            "Destination file content should remain unchanged"
-        );
+    /// Length of the copy of `src` left by an interrupted run, and whether
+    /// the part file or `dest` itself holds it. A `dest` that mvx didn't
+    /// necessarily write is only considered with `force`.
+    fn resume_point(&self, src: &Path, force: bool) -> Option<(u64, PathBuf)> {
+        let dest = force.then_some(&self.dest);
+        std::iter::once(&self.path)
+            .chain(dest)
+            .find_map(|path| Some((resume_offset(src, path)?, path.clone())))
     }
 
-    #[test]
-    fn copy_file_fails_with_nonexistent_source() {
-        let work_dir = tempdir().unwrap();
-        let src_path = work_dir.path().join("a");
-        let dest_content = "This is a test file";
-        let dest_path = create_temp_file(work_dir.path(), "b", dest_content);
+    /// Clone `src` into the part file, replacing any stale one.
+    fn reflink_from(&self, src: &Path) -> std::io::Result<()> {
+        remove_existing(&self.path)?;
+        reflink::reflink(src, &self.path)
+    }
 
-        assert!(!src_path.exists(), "Source file should not exist initially");
-        assert_error_with_msg(
-            copy_file(&src_path, "/dest/does/not/matter", false),
-            "does not exist",
-        );
-        assert_eq!(
-            fs::read_to_string(dest_path).unwrap(),
-            dest_content,
-            "Destination file content should remain unchanged"
-        );
+    /// Atomically replace `dest` with the part file, flushing both the file
+    /// and the rename to disk first if `sync` is set.
+    fn persist(mut self, sync: bool) -> std::io::Result<()> {
+        if sync {
+            sync_path(&self.path)?;
+        }
+        fs::rename(&self.path, &self.dest)?;
+        self.persisted = true;
+        if sync {
+            sync_parent(&self.dest)?;
+        }
+        Ok(())
     }
+}
 
-    #[test]
-    fn move_file_creates_intermediate_directories_automatically() {
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            if !self.keep.get() && fs::remove_file(&self.path).is_ok() {
+                log::warn!("{FAIL_MARK} Removed partial copy '{}'", self.path.display());
+            } else if self.keep.get() && self.path.exists() {
+                log::warn!(
+                    "{FAIL_MARK} Kept partial copy '{}' for --resume",
+                    self.path.display()
+                );
+            }
+        }
+        let mut parts = PART_FILES.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(i) = parts.iter().position(|p| *p == self.path) {
+            parts.swap_remove(i);
+        }
+    }
+}
+
+/// Size of each block of a partial copy compared with the source.
+const RESUME_BLOCK: u64 = 1 << 20;
+
+/// Blocks compared between the first and the last one, spread evenly.
+const RESUME_SAMPLES: u64 = 8;
+
+/// Length of `partial` if it looks like an interrupted copy of `src`: a
+/// regular file shorter than `src` that matches it in its first and last
+/// `RESUME_BLOCK` bytes and in `RESUME_SAMPLES` blocks in between. A zeroed
+/// last block never counts, since it's what a preallocated or truncated
+/// file holds whatever it was meant to be a copy of.
+fn resume_offset(src: &Path, partial: &Path) -> Option<u64> {
+    let len = fs::symlink_metadata(partial)
+        .ok()
+        .filter(fs::Metadata::is_file)?
+        .len();
+    if len == 0 || len >= fs::metadata(src).ok()?.len() {
+        return None;
+    }
+    let (src, partial) = (fs::File::open(src).ok()?, fs::File::open(partial).ok()?);
+    let block = RESUME_BLOCK.min(len);
+    let read = |file: &fs::File, start: u64| -> Option<Vec<u8>> {
+        let mut buf = vec![0; block as usize];
+        file.read_exact_at(&mut buf, start).ok()?;
+        Some(buf)
+    };
+    let last = len - block;
+    let tail = read(&partial, last)?;
+    if tail.iter().all(|&b| b == 0) || read(&src, last)? != tail {
+        return None;
+    }
+    let step = last / (RESUME_SAMPLES + 1);
+    (0..=RESUME_SAMPLES)
+        .map(|k| k * step)
+        .all(|start| read(&partial, start).is_some_and(|data| read(&src, start) == Some(data)))
+        .then_some(len)
+}
+
+/// Copy `src` into the part file, keeping holes according to `ctx.sparse`.
+/// Data goes through `copy_file_range` where the kernel supports it, and
+/// through a userspace buffer otherwise (always with `ctx.direct`). Bytes
+/// before `offset` are taken to be in the part file already.
+fn buffered_copy<F: Fn(u64)>(
+    src: &Path,
+    part: &PartFile,
+    offset: u64,
+    ctx: &Ctx,
+    pb: &indicatif::ProgressBar,
+    progress_cb: F,
+) -> anyhow::Result<TransferStats> {
+    let sparse = ctx.sparse;
+    let reader = open_maybe_direct(fs::OpenOptions::new().read(true), src, ctx.direct)?;
+    let len = reader.metadata()?.len();
+    // Owner-only until the copy completes and the source mode is applied.
+    let writer = open_maybe_direct(
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0)
+            .mode(0o600),
+        part.path(),
+        ctx.direct,
+    )?;
+    let segments = match sparse {
+        SparseMode::Never => vec![(0, len)],
+        SparseMode::Auto | SparseMode::Always => data_segments(&reader, len)?,
+    };
+    let segments: Vec<_> = segments
+        .into_iter()
+        .map(|(start, end)| (start.max(offset), end))
+        .filter(|(start, end)| start < end)
+        .collect();
+    // Zero blocks dropped by `Always` would otherwise stay allocated.
+    if sparse != SparseMode::Always {
+        preallocate(&writer, &segments)?;
+    }
+    let data_len: u64 = segments.iter().map(|(start, end)| end - start).sum();
+    if data_len < len - offset {
+        pb.set_prefix(format!("({} data)", indicatif::HumanBytes(data_len)));
+    }
+    advise_sequential(&reader);
+
+    if offset > 0 {
+        pb.set_position(offset);
+        pb.reset_eta();
+        progress_cb(offset);
+    }
+
+    let mut stats = TransferStats {
+        apparent_bytes: len - offset,
+        ..Default::default()
+    };
+    // Not worth the threads unless every stream gets a few buffers' worth.
+    let streams_pay_off = data_len
+        >= ctx
+            .streams
+            .saturating_mul(ctx.buffer_size)
+            .saturating_mul(4) as u64;
+    if ctx.streams > 1 && streams_pay_off {
+        // Streams leave gaps below the furthest write, so an interrupted
+        // part file isn't safe to resume from.
+        part.remove_on_failure();
+        let (copied, kernel) =
+            parallel_copy(&reader, &writer, &segments, offset..len, ctx, |pos| {
+                pb.set_position(pos);
+                progress_cb(pos);
+            })?;
+        stats.io_bytes += copied;
+        stats.kernel_bytes += kernel;
+    } else {
+        let mut cache = CacheDropper::default();
+        let mut report = |pos: u64| {
+            cache.advance(&reader, &writer, pos);
+            pb.set_position(pos);
+            progress_cb(pos);
+        };
+        let mut segments = segments;
+        // Zero detection needs to see the data, so it always goes through
+        // userspace. So does `--reflink=never`: `copy_file_range` may share
+        // extents on btrfs, XFS and NFS.
+        if sparse != SparseMode::Always && !ctx.direct && ctx.reflink != ReflinkMode::Never {
+            let (copied, rest) = kernel_copy(&reader, &writer, &segments, ctx, &mut report)?;
+            stats.io_bytes += copied;
+            stats.kernel_bytes += copied;
+            segments = rest;
+        }
+        stats.io_bytes += pipelined_copy(&reader, &writer, &segments, ctx, &mut report)?;
+    }
+
+    // Extends `dest` over a trailing hole, which no write reaches.
+    writer.set_len(len)?;
+    pb.set_position(len);
+    progress_cb(len);
+    Ok(stats)
+}
+
+/// Copy `segments` with `copy_file_range`, `chunk` bytes at a time so progress
+/// keeps flowing. Returns the bytes copied, and what's left to copy if the
+/// kernel can't do it for these files or stops short of the end.
+fn kernel_copy(
+    reader: &fs::File,
+    writer: &fs::File,
+    segments: &[(u64, u64)],
+    ctx: &Ctx,
+    report: &mut impl FnMut(u64),
+) -> std::io::Result<(u64, Vec<(u64, u64)>)> {
+    let chunk = ctx.buffer_size;
+    let mut copied = 0;
+    let remaining = |i: usize, pos: u64| {
+        let mut rest = vec![(pos, segments[i].1)];
+        rest.extend_from_slice(&segments[i + 1..]);
+        rest
+    };
+    for (i, &(start, end)) in segments.iter().enumerate() {
+        let mut pos = start;
+        while pos < end {
+            check_cancelled(ctx)?;
+            let want = usize::try_from(end - pos).map_or(chunk, |n| n.min(chunk));
+            match copy_range(reader, writer, pos, want) {
+                // Some filesystems return 0 for ranges they can't copy, and
+                // a buffered read tells that apart from a source that shrank.
+                Ok(0) => {
+                    log::debug!(
+                        "copy_file_range stopped at {pos} of {end}, falling back to buffered copy"
+                    );
+                    return Ok((copied, remaining(i, pos)));
+                }
+                Ok(n) => {
+                    copied += n as u64;
+                    pos += n as u64;
+                    report(pos);
+                }
+                Err(e)
+                    if e.raw_os_error().is_some_and(|e| {
+                        [libc::EXDEV, libc::ENOSYS, libc::EINVAL, libc::EOPNOTSUPP].contains(&e)
+                    }) =>
+                {
+                    log::debug!(
+                        "copy_file_range not available, falling back to buffered copy. Full error: {e:?}"
+                    );
+                    return Ok((copied, remaining(i, pos)));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok((copied, Vec::new()))
+}
+
+/// Error for a read that hit the end of the source before the size it had
+/// when the copy started.
+fn source_shrank(pos: u64) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        format!("source file shrank during copy, nothing left to read at byte {pos}"),
+    )
+}
+
+/// Copy `segments` through userspace: a reader thread fills a ring of
+/// `ctx.buffer_count` buffers while this thread writes them out, so neither
+/// side sits idle waiting for the other. The ring is no bigger than the data
+/// needs, and data that fits in one buffer is copied on this thread alone.
+/// Returns the bytes written.
+fn pipelined_copy(
+    reader: &fs::File,
+    writer: &fs::File,
+    segments: &[(u64, u64)],
+    ctx: &Ctx,
+    report: &mut impl FnMut(u64),
+) -> std::io::Result<u64> {
+    if segments.is_empty() {
+        return Ok(0);
+    }
+    let (direct, sparse) = (ctx.direct, ctx.sparse);
+    let data_len: u64 = segments.iter().map(|(start, end)| end - start).sum();
+    let needed = usize::try_from(data_len.div_ceil(ctx.buffer_size as u64)).unwrap_or(usize::MAX);
+    let count = ctx.buffer_count.clamp(1, needed.max(1));
+    if count == 1 {
+        let mut buf = AlignedBuf::new(ctx.buffer_size);
+        let size = buf.as_mut_slice().len();
+        let mut written = 0;
+        for &(start, end) in segments {
+            let mut pos = start;
+            while pos < end {
+                check_cancelled(ctx)?;
+                let want = usize::try_from(end - pos).map_or(size, |n| n.min(size));
+                let n = retry_buffered(reader, direct, || {
+                    reader.read_at(&mut buf.as_mut_slice()[..want], pos)
+                })?;
+                if n == 0 {
+                    return Err(source_shrank(pos));
+                }
+                written += retry_buffered(writer, direct, || {
+                    write_at(writer, pos, &buf.as_slice()[..n], sparse)
+                })?;
+                pos += n as u64;
+                report(pos);
+            }
+        }
+        return Ok(written);
+    }
+    let (full_tx, full_rx) = mpsc::sync_channel::<(u64, AlignedBuf, usize)>(count);
+    let (empty_tx, empty_rx) = mpsc::channel();
+    for _ in 0..count {
+        empty_tx.send(AlignedBuf::new(ctx.buffer_size)).unwrap();
+    }
+
+    std::thread::scope(|scope| {
+        // Stops early (without error) once the writer side hangs up.
+        let read_thread = scope.spawn(move || -> std::io::Result<()> {
+            for &(start, end) in segments {
+                let mut pos = start;
+                while pos < end {
+                    let Ok(mut buf) = empty_rx.recv() else {
+                        return Ok(());
+                    };
+                    let size = buf.as_mut_slice().len();
+                    let want = usize::try_from(end - pos).map_or(size, |n| n.min(size));
+                    let n = retry_buffered(reader, direct, || {
+                        reader.read_at(&mut buf.as_mut_slice()[..want], pos)
+                    })?;
+                    if n == 0 {
+                        return Err(source_shrank(pos));
+                    }
+                    if full_tx.send((pos, buf, n)).is_err() {
+                        return Ok(());
+                    }
+                    pos += n as u64;
+                }
+            }
+            Ok(())
+        });
+
+        // Owns the channel ends, so an early return unblocks the reader.
+        let write_result = (move || -> std::io::Result<u64> {
+            let mut written = 0;
+            for (pos, buf, n) in full_rx {
+                check_cancelled(ctx)?;
+                written += retry_buffered(writer, direct, || {
+                    write_at(writer, pos, &buf.as_slice()[..n], sparse)
+                })?;
+                let _ = empty_tx.send(buf);
+                report(pos + n as u64);
+            }
+            Ok(written)
+        })();
+        let read_result = read_thread.join().expect("reader thread panicked");
+        let written = write_result?;
+        read_result?;
+        Ok(written)
+    })
+}
+
+/// Copy `segments` as `ctx.streams` concurrent streams, each over its own
+/// byte range of `span` and each trying `copy_file_range` before pread/pwrite
+/// like a serial copy. `report` gets the position reached if all bytes
+/// covered so far were contiguous, holes included, so it ends at the end of
+/// `span`. Returns the bytes written, and how many of them the kernel copied.
+fn parallel_copy(
+    reader: &fs::File,
+    writer: &fs::File,
+    segments: &[(u64, u64)],
+    span: std::ops::Range<u64>,
+    ctx: &Ctx,
+    mut report: impl FnMut(u64),
+) -> std::io::Result<(u64, u64)> {
+    let (direct, sparse) = (ctx.direct, ctx.sparse);
+    let stride = (span.end - span.start)
+        .div_ceil(ctx.streams as u64)
+        .next_multiple_of(DIRECT_ALIGN as u64);
+    let failed = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel::<u64>();
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..(span.end - span.start).div_ceil(stride))
+            .map(|k| {
+                let range_start = span.start + k * stride;
+                let range_end = (range_start + stride).min(span.end);
+                let tx = tx.clone();
+                let failed = &failed;
+                scope.spawn(move || -> std::io::Result<(u64, u64)> {
+                    let mut pieces: Vec<_> = segments
+                        .iter()
+                        .map(|&(start, end)| (start.max(range_start), end.min(range_end)))
+                        .filter(|(start, end)| start < end)
+                        .collect();
+                    let mut cache = CacheDropper {
+                        started: range_start,
+                        dropped: range_start,
+                    };
+                    let mut cursor = range_start;
+                    let mut advance = |pos: u64| {
+                        cache.advance(reader, writer, pos);
+                        let _ = tx.send(pos - cursor);
+                        cursor = pos;
+                    };
+                    let fail = |_: &std::io::Error| failed.store(true, Ordering::Relaxed);
+
+                    let mut kernel = 0;
+                    if sparse != SparseMode::Always && !direct && ctx.reflink != ReflinkMode::Never
+                    {
+                        let (copied, rest) =
+                            kernel_copy(reader, writer, &pieces, ctx, &mut advance)
+                                .inspect_err(fail)?;
+                        kernel = copied;
+                        pieces = rest;
+                    }
+                    let mut buf = AlignedBuf::new(ctx.buffer_size);
+                    let size = buf.as_mut_slice().len();
+                    let mut written = kernel;
+                    for (start, end) in pieces {
+                        let mut pos = start;
+                        while pos < end {
+                            if failed.load(Ordering::Relaxed) {
+                                return Ok((written, kernel));
+                            }
+                            check_cancelled(ctx)?;
+                            let want = usize::try_from(end - pos).map_or(size, |n| n.min(size));
+                            let result = retry_buffered(reader, direct, || {
+                                reader.read_at(&mut buf.as_mut_slice()[..want], pos)
+                            })
+                            .and_then(|n| {
+                                if n == 0 {
+                                    Err(source_shrank(pos))
+                                } else {
+                                    Ok(n)
+                                }
+                            })
+                            .and_then(|n| {
+                                let w = retry_buffered(writer, direct, || {
+                                    write_at(writer, pos, &buf.as_slice()[..n], sparse)
+                                })?;
+                                Ok((n, w))
+                            });
+                            let (n, w) = result.inspect_err(fail)?;
+                            written += w;
+                            pos += n as u64;
+                            advance(pos);
+                        }
+                    }
+                    advance(range_end);
+                    Ok((written, kernel))
+                })
+            })
+            .collect();
+        drop(tx);
+
+        let mut covered = span.start;
+        for delta in rx {
+            covered += delta;
+            report(covered);
+        }
+        let (mut written, mut kernel) = (0, 0);
+        for worker in workers {
+            let (w, k) = worker.join().expect("copy stream panicked")?;
+            written += w;
+            kernel += k;
+        }
+        Ok((written, kernel))
+    })
+}
+
+/// Fail with `Cancelled` once Ctrl-C has been pressed.
+fn check_cancelled(ctx: &Ctx) -> std::io::Result<()> {
+    if ctx.ctrlc.load(Ordering::Relaxed) {
+        return Err(std::io::Error::other(Cancelled));
+    }
+    Ok(())
+}
+
+/// Heap buffer whose usable part starts on a `DIRECT_ALIGN` boundary, as
+/// O_DIRECT requires. Its size is rounded up to a multiple of that too.
+struct AlignedBuf {
+    storage: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let len = len.max(1).next_multiple_of(DIRECT_ALIGN);
+        let storage = vec![0u8; len + DIRECT_ALIGN];
+        let offset = storage.as_ptr().align_offset(DIRECT_ALIGN);
+        Self {
+            storage,
+            offset,
+            len,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.storage[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+const DIRECT_ALIGN: usize = 4096;
+
+#[cfg(target_os = "linux")]
+const O_DIRECT: libc::c_int = libc::O_DIRECT;
+#[cfg(not(target_os = "linux"))]
+const O_DIRECT: libc::c_int = 0;
+
+/// Open `path` with O_DIRECT if `direct` is set and the filesystem allows it
+/// (tmpfs, for one, doesn't), falling back to a regular open.
+fn open_maybe_direct(
+    options: &mut fs::OpenOptions,
+    path: &Path,
+    direct: bool,
+) -> std::io::Result<fs::File> {
+    if direct {
+        match options.clone().custom_flags(O_DIRECT).open(path) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                log::debug!("'{}' doesn't support O_DIRECT", path.display());
+            }
+            result => return result,
+        }
+    }
+    options.open(path)
+}
+
+/// Run `io`, and if O_DIRECT rejects it (an unaligned tail or offset), drop
+/// O_DIRECT from `file` and run it again through the page cache.
+fn retry_buffered<T>(
+    file: &fs::File,
+    direct: bool,
+    mut io: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    match io() {
+        Err(e) if direct && e.raw_os_error() == Some(libc::EINVAL) => {
+            let fd = file.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !O_DIRECT) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            io()
+        }
+        result => result,
+    }
+}
+
+/// Tell the kernel `file` is read once, front to back: read ahead
+/// aggressively and don't keep the pages around.
+#[cfg(target_os = "linux")]
+fn advise_sequential(file: &fs::File) {
+    for advice in [libc::POSIX_FADV_SEQUENTIAL, libc::POSIX_FADV_NOREUSE] {
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_sequential(_file: &fs::File) {}
+
+/// Evicts copied data from the page cache behind the write cursor, so a
+/// large copy doesn't push everything else out. Each window is flushed
+/// first, since dirty pages can't be dropped.
+#[derive(Default)]
+struct CacheDropper {
+    /// Writeback has been started for everything before this offset.
+    started: u64,
+    /// Everything before this offset has been flushed and dropped.
+    dropped: u64,
+}
+
+impl CacheDropper {
+    const WINDOW: u64 = 8 << 20;
+
+    #[cfg(target_os = "linux")]
+    fn advance(&mut self, reader: &fs::File, writer: &fs::File, pos: u64) {
+        if pos - self.started < Self::WINDOW {
+            return;
+        }
+        let (r, w) = (reader.as_raw_fd(), writer.as_raw_fd());
+        // Advisory only, so failures are ignored. Waiting on the previous
+        // window gives the current one time to be written back.
+        unsafe {
+            libc::sync_file_range(
+                w,
+                self.started as libc::off64_t,
+                (pos - self.started) as libc::off64_t,
+                libc::SYNC_FILE_RANGE_WRITE,
+            );
+            if self.started > self.dropped {
+                let (offset, len) = (
+                    self.dropped as libc::off64_t,
+                    (self.started - self.dropped) as libc::off64_t,
+                );
+                libc::sync_file_range(
+                    w,
+                    offset,
+                    len,
+                    libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                        | libc::SYNC_FILE_RANGE_WRITE
+                        | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+                );
+                libc::posix_fadvise(w, offset, len, libc::POSIX_FADV_DONTNEED);
+                libc::posix_fadvise(r, offset, len, libc::POSIX_FADV_DONTNEED);
+            }
+        }
+        self.dropped = self.started;
+        self.started = pos;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn advance(&mut self, _reader: &fs::File, _writer: &fs::File, _pos: u64) {}
+}
+
+/// Reserve space for `segments` of `file` up front, so a full disk fails
+/// right away instead of gigabytes in, and the data lands contiguously. The
+/// file keeps its length, which `--resume` relies on to tell how far the
+/// copy got. Filesystems that can't preallocate are silently skipped.
+#[cfg(target_os = "linux")]
+fn preallocate(file: &fs::File, segments: &[(u64, u64)]) -> std::io::Result<()> {
+    for &(start, end) in segments {
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                start as libc::off_t,
+                (end - start) as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP | libc::ENOSYS | libc::EINVAL) => Ok(()),
+                _ => Err(err),
+            };
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &fs::File, _segments: &[(u64, u64)]) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Copy up to `len` bytes at offset `pos` from `reader` to the same offset in
+/// `writer` without going through userspace.
+#[cfg(target_os = "linux")]
+fn copy_range(
+    reader: &fs::File,
+    writer: &fs::File,
+    pos: u64,
+    len: usize,
+) -> std::io::Result<usize> {
+    let mut off_in = pos as libc::loff_t;
+    let mut off_out = off_in;
+    let ret = unsafe {
+        libc::copy_file_range(
+            reader.as_raw_fd(),
+            &raw mut off_in,
+            writer.as_raw_fd(),
+            &raw mut off_out,
+            len,
+            0,
+        )
+    };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_range(
+    _reader: &fs::File,
+    _writer: &fs::File,
+    _pos: u64,
+    _len: usize,
+) -> std::io::Result<usize> {
+    Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+/// Block size for zero detection with `--sparse=always`.
+const SPARSE_BLOCK: usize = 4096;
+
+/// Write `data` at offset `pos`, leaving all-zero blocks unwritten (as holes)
+/// with `SparseMode::Always`. Returns the number of bytes written.
+fn write_at(writer: &fs::File, pos: u64, data: &[u8], sparse: SparseMode) -> std::io::Result<u64> {
+    if sparse != SparseMode::Always {
+        writer.write_all_at(data, pos)?;
+        return Ok(data.len() as u64);
+    }
+    let mut written = 0;
+    let mut offset = pos;
+    for block in data.chunks(SPARSE_BLOCK) {
+        if block.iter().any(|&b| b != 0) {
+            writer.write_all_at(block, offset)?;
+            written += block.len() as u64;
+        }
+        offset += block.len() as u64;
+    }
+    Ok(written)
+}
+
+/// Byte ranges of `file` that hold data, found with `SEEK_DATA`/`SEEK_HOLE`.
+/// A filesystem without hole support reports the whole file as data.
+fn data_segments(file: &fs::File, len: u64) -> std::io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let seek = |pos: u64, whence| {
+        let ret = unsafe { libc::lseek(fd, pos as libc::off_t, whence) };
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(ret as u64)
+        }
+    };
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    while pos < len {
+        let start = match seek(pos, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // Nothing but a hole up to the end.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && pos == 0 => {
+                return Ok(vec![(0, len)]);
+            }
+            Err(e) => return Err(e),
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.min(len);
+        segments.push((start, end));
+        pos = end;
+    }
+    Ok(segments)
+}
+
+/// Resolve the final path for file `src`: `dest` itself, or `dest/<name>` when
+/// `dest` is a directory (or doesn't exist and ends with '/').
+pub(crate) fn resolve_dest<Dest: AsRef<Path>>(src: &Path, dest: Dest) -> anyhow::Result<PathBuf> {
+    let mut dest = dest.as_ref().to_path_buf();
+    if dest.is_dir() || (!dest.exists() && dest.to_string_lossy().ends_with('/')) {
+        match src.file_name() {
+            Some(name) => dest.push(name),
+            None => bail!("Cannot get file name from '{}'", src.display()),
+        }
+    }
+    Ok(dest)
+}
+
+fn ensure_dest<Src: AsRef<Path>, Dest: AsRef<Path>>(
+    src: Src,
+    dest: Dest,
+    follow: bool,
+    force: bool,
+) -> anyhow::Result<PathBuf> {
+    let src = src.as_ref();
+    let Ok(src_meta) = metadata(src, follow) else {
+        bail!("Source '{}' does not exist", src.display());
+    };
+    ensure!(
+        !src_meta.is_dir(),
+        "Source '{}' exists but is not a file",
+        src.display()
+    );
+
+    let dest = resolve_dest(src, dest)?;
+    if fs::symlink_metadata(&dest).is_ok() {
+        ensure!(
+            !same_file(src, &dest),
+            "'{}' and '{}' are the same file",
+            src.display(),
+            dest.display()
+        );
+        ensure!(
+            !dest.is_dir(),
+            "Destination '{}' already exists and is not a file",
+            dest.display()
+        );
+        ensure!(
+            force,
+            "Destination '{}' already exists (use -f to overwrite)",
+            dest.display()
+        );
+    }
+    Ok(dest)
+}
+
+/// Check whether `a` and `b` refer to the same inode, either directly or
+/// through symlinks (e.g. hard links, `./a` vs `a`, or a link to the other).
+/// Returns `false` if either path can't be stat'ed.
+pub(crate) fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let id = |m: std::io::Result<fs::Metadata>| m.ok().map(|m| (m.dev(), m.ino()));
+    let followed = id(fs::metadata(a)).is_some_and(|ia| id(fs::metadata(b)) == Some(ia));
+    followed
+        || id(fs::symlink_metadata(a)).is_some_and(|ia| id(fs::symlink_metadata(b)) == Some(ia))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        assert_error_with_msg, assert_file_copied, assert_file_moved, assert_file_not_moved,
+        create_temp_file, hidden_multi_progress, is_root, skip_unless, test_ctx,
+    };
+    use serial_test::serial;
+    use std::fs;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::atomic::AtomicBool;
+    use tempfile::tempdir;
+
+    fn move_file<Src: AsRef<Path>, Dest: AsRef<Path>>(
+        src: Src,
+        dest: Dest,
+        force: bool,
+    ) -> anyhow::Result<String> {
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            force,
+            ..test_ctx(MoveOrCopy::Move, &mp, &ctrlc)
+        };
+        move_or_copy(src, dest, |_| {}, false, &ctx).map(|(msg, _)| msg)
+    }
+
+    fn copy_file<Src: AsRef<Path>, Dest: AsRef<Path>>(
+        src: Src,
+        dest: Dest,
+        force: bool,
+    ) -> anyhow::Result<String> {
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            force,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        move_or_copy(src, dest, |_| {}, false, &ctx).map(|(msg, _)| msg)
+    }
+
+    #[test]
+    fn move_file_succeeds_with_absolute_path() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = work_dir.path().join("b");
+
+        move_file(&src_path, &dest_path, false).unwrap();
+        assert_file_moved(&src_path, &dest_path, src_content);
+    }
+
+    #[test]
+    fn copy_file_succeeds_with_absolute_path() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "This is a test file");
+        let dest_path = work_dir.path().join("b");
+
+        copy_file(&src_path, &dest_path, false).unwrap();
+        assert_file_copied(&src_path, &dest_path);
+    }
+
+    #[test]
+    #[serial]
+    fn move_file_succeeds_with_relative_path() {
+        let work_dir = tempdir().unwrap();
+        std::env::set_current_dir(&work_dir).unwrap();
+        let src_content = "This is a test file";
+        fs::write("a", src_content).unwrap();
+
+        move_file("a", "b", false).unwrap();
+        assert_file_moved("a", "b", src_content);
+    }
+
+    #[test]
+    #[serial]
+    fn copy_file_succeeds_with_relative_path() {
+        let work_dir = tempdir().unwrap();
+        std::env::set_current_dir(&work_dir).unwrap();
+        let src_content = "This is a test file";
+        fs::write("a", src_content).unwrap();
+
+        copy_file("a", "b", false).unwrap();
+        assert_file_copied("a", "b");
+    }
+
+    #[test]
+    fn move_file_overwrites_existing_dest_with_force() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+
+        move_file(&src_path, &dest_path, true).unwrap();
+        assert_file_moved(&src_path, &dest_path, src_content);
+    }
+
+    #[test]
+    fn copy_file_overwrites_existing_dest_with_force() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+
+        copy_file(&src_path, &dest_path, true).unwrap();
+        assert_file_copied(&src_path, &dest_path);
+    }
+
+    #[test]
+    fn move_file_fails_without_force_when_dest_exists() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+
+        assert_error_with_msg(
+            move_file(&src_path, &dest_path, false),
+            "already exists (use -f to overwrite)",
+        );
+    }
+
+    #[test]
+    fn copy_file_fails_without_force_when_dest_exists() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = create_temp_file(work_dir.path(), "b", "This is a different file");
+
+        assert_error_with_msg(
+            copy_file(&src_path, &dest_path, false),
+            "already exists (use -f to overwrite)",
+        );
+    }
+
+    #[test]
+    fn move_file_fails_with_nonexistent_source() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("a");
+        let dest_content = "This is a test file";
+        let dest_path = create_temp_file(work_dir.path(), "b", dest_content);
+
+        assert!(!src_path.exists(), "Source file should not exist initially");
+        assert_error_with_msg(
+            move_file(&src_path, "/dest/does/not/matter", false),
+            "does not exist",
+        );
+        assert_eq!(
+            fs::read_to_string(dest_path).unwrap(),
+            dest_content,
+            "Destination file content should remain unchanged"
+        );
+    }
+
+    #[test]
+    fn copy_file_fails_with_nonexistent_source() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("a");
+        let dest_content = "This is a test file";
+        let dest_path = create_temp_file(work_dir.path(), "b", dest_content);
+
+        assert!(!src_path.exists(), "Source file should not exist initially");
+        assert_error_with_msg(
+            copy_file(&src_path, "/dest/does/not/matter", false),
+            "does not exist",
+        );
+        assert_eq!(
+            fs::read_to_string(dest_path).unwrap(),
+            dest_content,
+            "Destination file content should remain unchanged"
+        );
+    }
+
+    #[test]
+    fn move_file_creates_intermediate_directories_automatically() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = work_dir
+            .path()
+            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+
+        move_file(&src_path, &dest_path, false).unwrap();
+        assert_file_moved(&src_path, &dest_path, src_content);
+    }
+
+    #[test]
+    fn copy_file_creates_intermediate_directories_automatically() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = work_dir
+            .path()
+            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+
+        copy_file(&src_path, &dest_path, false).unwrap();
+        assert_file_copied(&src_path, &dest_path);
+    }
+
+    #[test]
+    fn move_file_into_existing_directory() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_dir = work_dir.path().join("dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        move_file(&src_path, &dest_dir, false).unwrap();
+        assert_file_moved(&src_path, dest_dir.join("a"), src_content);
+    }
+
+    #[test]
+    fn copy_file_into_existing_directory() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        let dest_dir = work_dir.path().join("dest_dir");
+        fs::create_dir_all(&dest_dir).unwrap();
+
+        copy_file(&src_path, &dest_dir, false).unwrap();
+        assert_file_copied(&src_path, dest_dir.join("a"));
+    }
+
+    #[test]
+    fn move_fails_when_source_is_directory() {
+        let src_dir = tempdir().unwrap();
+
+        assert_error_with_msg(
+            move_file(src_dir.path(), "/dest/does/not/matter", false),
+            "not a file",
+        );
+    }
+
+    #[test]
+    fn move_file_fails_when_cant_create_intermediate_directories() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        create_temp_file(work_dir.path(), "b/c/d", "");
+        let dest_path = work_dir
+            .path()
+            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+
+        assert_error_with_msg(move_file(&src_path, &dest_path, false), "Not a directory");
+        assert_file_not_moved(&src_path, &dest_path);
+    }
+
+    #[test]
+    fn copy_file_fails_when_cant_create_intermediate_directories() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        create_temp_file(work_dir.path(), "b/c/d", "");
+        let dest_path = work_dir
+            .path()
+            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+
+        assert_error_with_msg(copy_file(&src_path, &dest_path, false), "Not a directory");
+        assert_file_not_moved(&src_path, &dest_path);
+    }
+
+    #[test]
+    fn copy_file_onto_itself_with_force_keeps_source() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+
+        assert_error_with_msg(copy_file(&src_path, &src_path, true), "are the same file");
+        assert_eq!(fs::read_to_string(&src_path).unwrap(), src_content);
+    }
+
+    #[test]
+    fn move_file_onto_itself_fails() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+
+        assert_error_with_msg(move_file(&src_path, &src_path, true), "are the same file");
+        assert_eq!(fs::read_to_string(&src_path).unwrap(), src_content);
+    }
+
+    #[test]
+    fn copy_file_fails_when_dest_is_hard_link_to_source() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = work_dir.path().join("b");
+        fs::hard_link(&src_path, &dest_path).unwrap();
+
+        assert_error_with_msg(copy_file(&src_path, &dest_path, true), "are the same file");
+        assert_error_with_msg(move_file(&src_path, &dest_path, true), "are the same file");
+        assert_eq!(fs::read_to_string(&src_path).unwrap(), src_content);
+        assert_eq!(fs::read_to_string(&dest_path).unwrap(), src_content);
+    }
+
+    #[test]
+    fn copy_file_fails_when_dest_is_symlink_to_source() {
+        let work_dir = tempdir().unwrap();
+        let src_content = "This is a test file";
+        let src_path = create_temp_file(work_dir.path(), "a", src_content);
+        let dest_path = work_dir.path().join("b");
+        std::os::unix::fs::symlink(&src_path, &dest_path).unwrap();
+
+        assert_error_with_msg(copy_file(&src_path, &dest_path, true), "are the same file");
+        assert_eq!(fs::read_to_string(&src_path).unwrap(), src_content);
+    }
+
+    #[test]
+    fn move_file_fails_when_source_is_symlink_to_dest() {
+        let work_dir = tempdir().unwrap();
+        let dest_content = "This is a test file";
+        let dest_path = create_temp_file(work_dir.path(), "b", dest_content);
+        let src_path = work_dir.path().join("a");
+        std::os::unix::fs::symlink(&dest_path, &src_path).unwrap();
+
+        assert_error_with_msg(move_file(&src_path, &dest_path, true), "are the same file");
+        assert_error_with_msg(copy_file(&src_path, &dest_path, true), "are the same file");
+        assert_eq!(fs::read_to_string(&dest_path).unwrap(), dest_content);
+    }
+
+    #[test]
+    #[serial]
+    fn copy_file_fails_when_dest_is_dot_slash_alias_of_source() {
+        let work_dir = tempdir().unwrap();
+        std::env::set_current_dir(&work_dir).unwrap();
+        let src_content = "This is a test file";
+        fs::write("a", src_content).unwrap();
+
+        assert_error_with_msg(copy_file("./a", "a", true), "are the same file");
+        assert_error_with_msg(move_file("a", "./a", true), "are the same file");
+        assert_eq!(fs::read_to_string("a").unwrap(), src_content);
+    }
+
+    fn mode_of<P: AsRef<Path>>(path: P) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    fn set_mode<P: AsRef<Path>>(path: P, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn copy_file_preserves_permission_bits() {
+        let work_dir = tempdir().unwrap();
+        for (name, mode) in [("exec", 0o755), ("secret", 0o600), ("odd", 0o741)] {
+            let src_path = create_temp_file(work_dir.path(), name, "content");
+            set_mode(&src_path, mode);
+            let dest_path = work_dir.path().join(format!("{name}.copy"));
+
+            copy_file(&src_path, &dest_path, false).unwrap();
+            assert_eq!(mode_of(&dest_path), mode, "mode of '{name}'");
+        }
+    }
+
+    #[test]
+    fn copy_file_overwriting_with_force_takes_source_mode() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "new");
+        set_mode(&src_path, 0o640);
+        let dest_path = create_temp_file(work_dir.path(), "b", "old");
+        set_mode(&dest_path, 0o777);
+
+        copy_file(&src_path, &dest_path, true).unwrap();
+        assert_eq!(mode_of(&dest_path), 0o640);
+    }
+
+    #[test]
+    fn copy_file_keeps_setuid_and_setgid_when_owner_matches() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        set_mode(&src_path, 0o6755);
+        let dest_path = work_dir.path().join("b");
+
+        copy_file(&src_path, &dest_path, false).unwrap();
+        assert_eq!(mode_of(&dest_path), 0o6755);
+    }
+
+    #[test]
+    fn copy_file_as_root_preserves_owner_and_setuid() {
+        use std::os::unix::fs::MetadataExt;
+        skip_unless!(is_root(), "needs root to chown the source to someone else");
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        std::os::unix::fs::chown(&src_path, Some(54321), Some(54322)).unwrap();
+        set_mode(&src_path, 0o6755);
+        let dest_path = work_dir.path().join("b");
+
+        copy_file(&src_path, &dest_path, false).unwrap();
+        let dest_meta = fs::metadata(&dest_path).unwrap();
+        assert_eq!((dest_meta.uid(), dest_meta.gid()), (54321, 54322));
+        assert_eq!(mode_of(&dest_path), 0o6755);
+    }
+
+    #[test]
+    fn copy_file_keeps_group_the_user_belongs_to() {
+        use std::os::unix::fs::MetadataExt;
+        let egid = unsafe { libc::getegid() };
+        // Root may use any group; anyone else needs a second group of theirs.
+        let gid = if is_root() {
+            Some(54322)
+        } else {
+            let mut groups = vec![0; 256];
+            let n = unsafe { libc::getgroups(256, groups.as_mut_ptr()) };
+            groups.truncate(usize::try_from(n).unwrap_or(0));
+            groups.into_iter().find(|&g| g != egid)
+        };
+        skip_unless!(gid.is_some(), "needs membership in a second group");
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        std::os::unix::fs::chown(&src_path, None, gid).unwrap();
+        let dest_path = work_dir.path().join("b");
+
+        copy_file(&src_path, &dest_path, false).unwrap();
+        assert_eq!(fs::metadata(&dest_path).unwrap().gid(), gid.unwrap());
+    }
+
+    fn set_times<P: AsRef<Path>>(path: P, accessed_secs: u64, modified_secs: u64, nanos: u32) {
+        use std::time::{Duration, SystemTime};
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::new(secs, nanos);
+        fs::File::open(path)
+            .unwrap()
+            .set_times(
+                fs::FileTimes::new()
+                    .set_accessed(at(accessed_secs))
+                    .set_modified(at(modified_secs)),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn copy_file_preserves_timestamps_with_nanoseconds() {
+        use std::os::unix::fs::MetadataExt;
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        set_times(&src_path, 1_500_000_000, 1_600_000_000, 123_456_789);
+        let dest_path = work_dir.path().join("b");
+
+        copy_file(&src_path, &dest_path, false).unwrap();
+        let dest_meta = fs::metadata(&dest_path).unwrap();
+        assert_eq!(dest_meta.atime(), 1_500_000_000);
+        assert_eq!(dest_meta.mtime(), 1_600_000_000);
+        assert_eq!(dest_meta.mtime_nsec(), 123_456_789);
+    }
+
+    #[test]
+    fn copy_symlink_recreates_link_with_exact_text() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("link");
+        std::os::unix::fs::symlink("../does/not/exist", &src_path).unwrap();
+        let dest_path = work_dir.path().join("sub/link");
+
+        copy_file(&src_path, &dest_path, false).unwrap();
+        assert_eq!(
+            fs::read_link(&dest_path).unwrap(),
+            PathBuf::from("../does/not/exist")
+        );
+        assert!(fs::symlink_metadata(&src_path).unwrap().is_symlink());
+    }
+
+    #[test]
+    fn copy_symlink_replaces_dest_with_force() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("link");
+        std::os::unix::fs::symlink("target", &src_path).unwrap();
+        let dest_path = create_temp_file(work_dir.path(), "dest", "existing");
+
+        copy_file(&src_path, &dest_path, true).unwrap();
+        assert_eq!(fs::read_link(&dest_path).unwrap(), PathBuf::from("target"));
+        assert_eq!(
+            fs::symlink_metadata(&dest_path).unwrap().mtime(),
+            fs::symlink_metadata(&src_path).unwrap().mtime()
+        );
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn copy_symlink_to_file_does_not_copy_target() {
+        let work_dir = tempdir().unwrap();
+        create_temp_file(work_dir.path(), "target", "content");
+        let src_path = work_dir.path().join("link");
+        std::os::unix::fs::symlink("target", &src_path).unwrap();
+        let dest_dir = work_dir.path().join("dest");
+        fs::create_dir(&dest_dir).unwrap();
+
+        copy_file(&src_path, &dest_dir, false).unwrap();
+        let dest_path = dest_dir.join("link");
+        assert!(fs::symlink_metadata(&dest_path).unwrap().is_symlink());
+        assert_eq!(fs::read_link(&dest_path).unwrap(), PathBuf::from("target"));
+    }
+
+    #[test]
+    fn move_symlink_moves_link_not_target() {
+        let work_dir = tempdir().unwrap();
+        let target = create_temp_file(work_dir.path(), "target", "content");
+        let src_path = work_dir.path().join("link");
+        std::os::unix::fs::symlink(&target, &src_path).unwrap();
+        let dest_path = work_dir.path().join("moved");
+
+        move_file(&src_path, &dest_path, false).unwrap();
+        assert!(fs::symlink_metadata(&src_path).is_err());
+        assert_eq!(fs::read_link(&dest_path).unwrap(), target);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "content");
+    }
+
+    #[test]
+    fn copy_file_over_dangling_symlink_requires_force() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        let dest_path = work_dir.path().join("b");
+        std::os::unix::fs::symlink("nowhere", &dest_path).unwrap();
+
+        assert_error_with_msg(
+            copy_file(&src_path, &dest_path, false),
+            "already exists (use -f to overwrite)",
+        );
+        copy_file(&src_path, &dest_path, true).unwrap();
+        assert!(fs::symlink_metadata(&dest_path).unwrap().is_file());
+        assert_file_copied(&src_path, &dest_path);
+    }
+
+    fn mkfifo(path: &Path) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o640) }, 0);
+    }
+
+    #[test]
+    fn copy_fifo_recreates_it_without_reading() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("fifo");
+        mkfifo(&src_path);
+        let dest_path = work_dir.path().join("copy");
+
+        // Reading the FIFO would block forever with no writer.
+        copy_file(&src_path, &dest_path, false).unwrap();
+        let meta = fs::symlink_metadata(&dest_path).unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.mode() & 0o7777, 0o640);
+        assert!(src_path.exists());
+    }
+
+    #[test]
+    fn move_fifo_moves_it() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("fifo");
+        mkfifo(&src_path);
+        let dest_path = work_dir.path().join("moved");
+
+        move_file(&src_path, &dest_path, false).unwrap();
+        assert!(fs::symlink_metadata(&src_path).is_err());
+        assert!(
+            fs::symlink_metadata(&dest_path)
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+    }
+
+    #[test]
+    fn copy_device_node_as_root() {
+        skip_unless!(is_root(), "needs root to create device nodes");
+        let work_dir = tempdir().unwrap();
+        let dest_path = work_dir.path().join("null");
+
+        copy_file("/dev/null", &dest_path, false).unwrap();
+        let meta = fs::symlink_metadata(&dest_path).unwrap();
+        assert!(meta.file_type().is_char_device());
+        assert_eq!(meta.rdev(), fs::metadata("/dev/null").unwrap().rdev());
+    }
+
+    #[test]
+    fn copy_device_node_without_root_keeps_dest() {
+        skip_unless!(!is_root(), "root can create device nodes");
+        let work_dir = tempdir().unwrap();
+        let dest_path = create_temp_file(work_dir.path(), "null", "existing");
+
+        let msg = copy_file("/dev/null", &dest_path, true).unwrap();
+        assert!(msg.contains("Skipped device node"));
+        assert!(msg.contains(FAIL_MARK));
+        assert_eq!(fs::read_to_string(&dest_path).unwrap(), "existing");
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn copy_fifo_replaces_dest_with_force() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("fifo");
+        mkfifo(&src_path);
+        let dest_path = create_temp_file(work_dir.path(), "copy", "existing");
+
+        copy_file(&src_path, &dest_path, true).unwrap();
+        assert!(
+            fs::symlink_metadata(&dest_path)
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn copy_socket_is_skipped() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&src_path).unwrap();
+        let dest_path = work_dir.path().join("copy");
+
+        let msg = copy_file(&src_path, &dest_path, false).unwrap();
+        assert!(msg.contains("Skipped socket"));
+        assert!(fs::symlink_metadata(&src_path).is_ok());
+        assert!(fs::symlink_metadata(&dest_path).is_err());
+    }
+
+    fn copy_sparse(src: &Path, dest: &Path, sparse: SparseMode) -> TransferStats {
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            sparse,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        move_or_copy(src, dest, |_| {}, false, &ctx).unwrap().1
+    }
+
+    /// 8 MiB file with 64 KiB of data in the middle and holes around it.
+    fn create_sparse_file(path: &Path) {
+        let mut file = fs::File::create(path).unwrap();
+        file.set_len(8 << 20).unwrap();
+        file.seek(SeekFrom::Start(4 << 20)).unwrap();
+        file.write_all(&[0xab; 64 << 10]).unwrap();
+    }
+
+    fn allocated(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    #[test]
+    fn copy_keeps_holes() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("sparse");
+        create_sparse_file(&src_path);
+        skip_unless!(
+            allocated(&src_path) < 8 << 20,
+            "filesystem doesn't support holes"
+        );
+        let dest_path = work_dir.path().join("copy");
+
+        let stats = copy_sparse(&src_path, &dest_path, SparseMode::Auto);
+        assert_eq!(fs::read(&src_path).unwrap(), fs::read(&dest_path).unwrap());
+        assert!(allocated(&dest_path) < 1 << 20);
+        assert!(stats.io_bytes < 1 << 20);
+        assert_eq!(stats.apparent_bytes, 8 << 20);
+    }
+
+    #[test]
+    fn copy_sparse_never_fills_holes() {
         let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_path = work_dir
-            .path()
-            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+        let src_path = work_dir.path().join("sparse");
+        create_sparse_file(&src_path);
+        let dest_path = work_dir.path().join("copy");
 
-        move_file(&src_path, &dest_path, false).unwrap();
-        assert_file_moved(&src_path, &dest_path, src_content);
+        let stats = copy_sparse(&src_path, &dest_path, SparseMode::Never);
+        assert_eq!(fs::read(&src_path).unwrap(), fs::read(&dest_path).unwrap());
+        assert!(allocated(&dest_path) >= 8 << 20);
+        assert_eq!(stats.io_bytes, 8 << 20);
     }
 
     #[test]
-    fn copy_file_creates_intermediate_directories_automatically() {
+    fn copy_sparse_always_punches_zero_blocks() {
         let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_path = work_dir
-            .path()
-            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+        let src_path = work_dir.path().join("dense");
+        let mut data = vec![0u8; 4 << 20];
+        data[..100].fill(1);
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("copy");
 
-        copy_file(&src_path, &dest_path, false).unwrap();
+        let stats = copy_sparse(&src_path, &dest_path, SparseMode::Always);
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert_eq!(stats.io_bytes, SPARSE_BLOCK as u64);
+        assert_eq!(stats.kernel_bytes, 0);
+        assert!(allocated(&dest_path) < 1 << 20);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn copy_uses_copy_file_range() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..3 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+
+        let stats = copy_sparse(&src_path, &dest_path, SparseMode::Auto);
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert_eq!(stats.io_bytes, data.len() as u64);
+        assert_eq!(stats.kernel_bytes, data.len() as u64);
+    }
+
+    fn copy_reflink(
+        src: &Path,
+        dest: &Path,
+        reflink: ReflinkMode,
+    ) -> anyhow::Result<(String, TransferStats)> {
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            reflink,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        move_or_copy(src, dest, |_| {}, false, &ctx)
+    }
+
+    #[test]
+    fn move_reflinks_when_rename_crosses_mounts() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        // Needs a directory on another mount of the same filesystem, such as
+        // a btrfs subvolume, that rename refuses but cloning can reach.
+        let clone_only = |dir: &Path| {
+            let other = tempfile::tempdir_in(dir).ok()?;
+            let probe = other.path().join("probe");
+            let crosses = fs::hard_link(&src_path, &probe)
+                .is_err_and(|e| e.kind() == std::io::ErrorKind::CrossesDevices);
+            (crosses && reflink::reflink(&src_path, &probe).is_ok()).then_some(other)
+        };
+        let other = [env!("CARGO_MANIFEST_DIR"), "/dev/shm", "/var/tmp"]
+            .into_iter()
+            .find_map(|dir| clone_only(Path::new(dir)));
+        skip_unless!(
+            other.is_some(),
+            "no mount reachable by cloning but not by rename"
+        );
+        let dest_path = other.unwrap().path().join("b");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = test_ctx(MoveOrCopy::Move, &mp, &ctrlc);
+        let (msg, stats) = move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx).unwrap();
+        assert!(msg.contains("Reflinked"));
+        assert_file_moved(&src_path, &dest_path, "content");
+        assert_eq!(stats.reflinked_file_count, 1);
+        assert_eq!(stats.io_bytes, 0);
+    }
+
+    #[test]
+    fn copy_reflink_always_clones_or_fails() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        let dest_path = work_dir.path().join("b");
+
+        let result = copy_reflink(&src_path, &dest_path, ReflinkMode::Always);
+        if reflink::reflink(&src_path, work_dir.path().join("probe")).is_ok() {
+            let (_, stats) = result.unwrap();
+            assert_file_copied(&src_path, &dest_path);
+            assert_eq!(stats.reflinked_file_count, 1);
+            assert_eq!(stats.io_bytes, 0);
+        } else {
+            assert_error_with_msg(result.map(|(msg, _)| msg), "--reflink=always");
+            assert!(!dest_path.exists());
+        }
+    }
+
+    #[test]
+    fn copy_reflink_never_copies_data() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        let dest_path = work_dir.path().join("b");
+
+        let (_, stats) = copy_reflink(&src_path, &dest_path, ReflinkMode::Never).unwrap();
         assert_file_copied(&src_path, &dest_path);
+        assert_eq!(stats.fast_path_file_count, 0);
+        assert_eq!(stats.io_bytes, 7);
+        assert_eq!(stats.kernel_bytes, 0);
     }
 
     #[test]
-    fn move_file_into_existing_directory() {
+    fn hard_link_across_devices_errors_or_falls_back() {
         let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        let dest_dir = work_dir.path().join("dest_dir");
-        fs::create_dir_all(&dest_dir).unwrap();
+        let other_dev = tempfile::tempdir_in("/dev/shm").ok();
+        skip_unless!(
+            other_dev
+                .as_ref()
+                .is_some_and(|d| !crate::dir::same_device(work_dir.path(), d.path())),
+            "needs /dev/shm on a separate device"
+        );
+        let other_dev = other_dev.unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "content");
+        let dest_path = create_temp_file(other_dev.path(), "b", "existing");
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = |copy_fallback| Ctx {
+            force: true,
+            link: LinkMode::Hard { copy_fallback },
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
 
-        move_file(&src_path, &dest_dir, false).unwrap();
-        assert_file_moved(&src_path, dest_dir.join("a"), src_content);
+        assert_error_with_msg(
+            move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx(false)).map(|(msg, _)| msg),
+            "--link-fallback",
+        );
+        // The failed link leaves the existing file alone.
+        assert_eq!(fs::read_to_string(&dest_path).unwrap(), "existing");
+        assert_eq!(fs::read_dir(other_dev.path()).unwrap().count(), 1);
+        move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx(true)).unwrap();
+        assert_file_copied(&src_path, &dest_path);
     }
 
     #[test]
-    fn copy_file_into_existing_directory() {
+    #[cfg(target_os = "linux")]
+    fn copy_fails_fast_when_dest_is_too_small() {
+        let work_dir = tempdir().unwrap();
+        let probe = fs::File::create(work_dir.path().join("probe")).unwrap();
+        skip_unless!(
+            unsafe { libc::fallocate(probe.as_raw_fd(), 0, 0, 4096) } == 0,
+            "filesystem can't preallocate"
+        );
+        let dir = CString::new(work_dir.path().as_os_str().as_bytes()).unwrap();
+        let mut vfs: libc::statvfs = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::statvfs(dir.as_ptr(), &raw mut vfs) }, 0);
+        skip_unless!(
+            vfs.f_bavail.saturating_mul(vfs.f_frsize) < 1 << 42,
+            "4 TiB would actually fit"
+        );
+        // 4 TiB apparent, written out in full with `Never`.
+        let src_path = work_dir.path().join("huge");
+        fs::File::create(&src_path)
+            .unwrap()
+            .set_len(1 << 42)
+            .unwrap();
+        let dest_path = create_temp_file(work_dir.path(), "copy", "old");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            force: true,
+            sparse: SparseMode::Never,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let err = move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx).unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+        // The failed copy neither touched `dest` nor left its part file behind.
+        assert_eq!(fs::read_to_string(&dest_path).unwrap(), "old");
+        assert!(!work_dir.path().join(".copy.mvx-part").exists());
+    }
+
+    #[test]
+    fn part_file_name_fits_name_max() {
+        use std::ffi::OsStr;
+        assert_eq!(PartFile::name(OsStr::new("b")), ".b.mvx-part");
+        let fits = "a".repeat(NAME_MAX - 10);
+        assert_eq!(
+            PartFile::name(OsStr::new(&fits)),
+            format!(".{fits}.mvx-part").as_str()
+        );
+
+        // Names sharing the kept prefix still get distinct part files.
+        let long = |last: &str| PartFile::name(OsStr::new(&format!("{}{last}", "é".repeat(125))));
+        let (a, b) = (long("a"), long("b"));
+        assert_ne!(a, b);
+        assert_eq!(a, long("a"));
+        for name in [a, b] {
+            assert!(name.len() <= NAME_MAX);
+            let name = name.into_string().unwrap();
+            assert!(name.starts_with(".éé") && name.ends_with(".mvx-part"));
+        }
+    }
+
+    #[test]
+    fn copy_with_sync_creates_missing_parents() {
         let work_dir = tempdir().unwrap();
         let src_path = create_temp_file(work_dir.path(), "a", "content");
-        let dest_dir = work_dir.path().join("dest_dir");
-        fs::create_dir_all(&dest_dir).unwrap();
+        let dest_dir = work_dir.path().join("new/sub/");
 
-        copy_file(&src_path, &dest_dir, false).unwrap();
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            sync: true,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        move_or_copy(&src_path, &dest_dir, |_| {}, false, &ctx).unwrap();
         assert_file_copied(&src_path, dest_dir.join("a"));
+        // Already there, so nothing to create or sync.
+        create_dirs(&dest_dir, true).unwrap();
     }
 
     #[test]
-    fn move_fails_when_source_is_directory() {
-        let src_dir = tempdir().unwrap();
+    fn copy_file_with_longest_name() {
+        let work_dir = tempdir().unwrap();
+        let name = "x".repeat(NAME_MAX);
+        let src_path = create_temp_file(work_dir.path(), &name, "content");
+        let dest_dir = work_dir.path().join("dest");
+        fs::create_dir(&dest_dir).unwrap();
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            reflink: ReflinkMode::Never,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        move_or_copy(&src_path, &dest_dir, |_| {}, false, &ctx).unwrap();
+        assert_file_copied(&src_path, dest_dir.join(&name));
+        assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn copy_replaces_stale_part_file() {
+        let work_dir = tempdir().unwrap();
+        let src_path = create_temp_file(work_dir.path(), "a", "This is a test file");
+        let part_path = create_temp_file(work_dir.path(), ".b.mvx-part", "leftover junk data");
+        let dest_path = work_dir.path().join("b");
+
+        for reflink in [ReflinkMode::Auto, ReflinkMode::Never] {
+            let mp = hidden_multi_progress();
+            let ctrlc = AtomicBool::new(false);
+            let ctx = Ctx {
+                force: true,
+                reflink,
+                ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+            };
+            move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx).unwrap();
+            assert_file_copied(&src_path, &dest_path);
+            assert!(!part_path.exists());
+            fs::write(&part_path, "leftover junk data").unwrap();
+        }
+    }
+
+    #[test]
+    fn cancelled_copy_removes_partial_file() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..4u32 << 20).map(|i| (i % 251) as u8 | 1).collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+        let part_path = work_dir.path().join(".b.mvx-part");
+
+        // Kernel copy and userspace pipeline.
+        for sparse in [SparseMode::Auto, SparseMode::Always] {
+            let mp = hidden_multi_progress();
+            let ctrlc = AtomicBool::new(false);
+            let ctx = Ctx {
+                sparse,
+                buffer_size: 64 << 10,
+                ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+            };
+            // Pressed once the first MiB is in, from the copying thread, so
+            // the next check is guaranteed to see it.
+            let cancelled_at = std::cell::Cell::new(0);
+            let progress = |pos: u64| {
+                if pos >= 1 << 20 && cancelled_at.get() == 0 {
+                    assert!(fs::metadata(&part_path).unwrap().len() > 0);
+                    cancelled_at.set(pos);
+                    ctrlc.store(true, Ordering::Relaxed);
+                }
+            };
+            let err = move_or_copy(&src_path, &dest_path, progress, false, &ctx).unwrap_err();
+            assert!(crate::is_cancelled(&err), "{sparse:?}: {err:?}");
+            assert!((1 << 20..data.len() as u64).contains(&cancelled_at.get()));
+            assert!(!dest_path.exists());
+            assert!(!part_path.exists());
+            assert_eq!(fs::read(&src_path).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn copy_fails_when_source_shrinks() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..4u32 << 20).map(|i| (i % 251) as u8 | 1).collect();
+        let src_path = work_dir.path().join("a");
+        let dest_path = work_dir.path().join("b");
 
+        // Kernel copy and userspace pipeline.
+        for sparse in [SparseMode::Auto, SparseMode::Always] {
+            fs::write(&src_path, &data).unwrap();
+            let mp = hidden_multi_progress();
+            let ctrlc = AtomicBool::new(false);
+            let ctx = Ctx {
+                sparse,
+                buffer_size: 64 << 10,
+                ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+            };
+            let truncated = std::cell::Cell::new(false);
+            let progress = |pos: u64| {
+                if pos >= 1 << 20 && !truncated.replace(true) {
+                    fs::File::options()
+                        .write(true)
+                        .open(&src_path)
+                        .unwrap()
+                        .set_len(1 << 20)
+                        .unwrap();
+                }
+            };
+            let err = move_or_copy(&src_path, &dest_path, progress, false, &ctx).unwrap_err();
+            assert!(format!("{err:?}").contains("shrank"), "{sparse:?}: {err:?}");
+            assert!(!dest_path.exists());
+        }
+    }
+
+    #[test]
+    fn cancelled_streamed_copy_removes_part_file() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..4u32 << 20).map(|i| (i % 251) as u8 | 1).collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+
+        let mp = hidden_multi_progress();
+        // Pressed before the copy starts: progress arrives on another thread
+        // than the workers, which could finish before seeing a later press.
+        let ctrlc = AtomicBool::new(true);
+        let ctx = Ctx {
+            reflink: ReflinkMode::Never,
+            streams: 4,
+            buffer_size: 64 << 10,
+            // Not even kept for this: streams may leave gaps behind them.
+            resume: true,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let err = move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx).unwrap_err();
+        assert!(crate::is_cancelled(&err), "{err:?}");
+        assert!(!dest_path.exists());
+        assert!(!work_dir.path().join(".b.mvx-part").exists());
+        assert_eq!(fs::read(&src_path).unwrap(), data);
+    }
+
+    #[test]
+    fn resume_continues_cancelled_copy() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..3u32 << 20).map(|i| (i % 251) as u8 | 1).collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+        let part_path = work_dir.path().join(".b.mvx-part");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            // Cloning would finish the copy before it can be cancelled.
+            reflink: ReflinkMode::Never,
+            resume: true,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let cancel = |_| ctrlc.store(true, Ordering::Relaxed);
+        let err = move_or_copy(&src_path, &dest_path, cancel, false, &ctx).unwrap_err();
+        assert!(crate::is_cancelled(&err), "{err:?}");
+        assert!(!dest_path.exists());
+        let offset = fs::metadata(&part_path).unwrap().len();
+        assert!(offset > 0 && offset < data.len() as u64);
+
+        ctrlc.store(false, Ordering::Relaxed);
+        let first = std::cell::Cell::new(None);
+        let (_, stats) = move_or_copy(
+            &src_path,
+            &dest_path,
+            |pos| first.set(first.get().or(Some(pos))),
+            false,
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert!(!part_path.exists());
+        assert_eq!(first.get(), Some(offset));
+        assert_eq!(stats.io_bytes, data.len() as u64 - offset);
+    }
+
+    #[test]
+    fn resume_continues_partial_dest_only_with_force() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..(1u32 << 20) + 5).map(|i| (i % 251) as u8).collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = |force| Ctx {
+            reflink: ReflinkMode::Never,
+            resume: true,
+            force,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        // Not written by mvx as far as it can tell, so not touched without -f.
+        fs::write(&dest_path, &data[..5000]).unwrap();
         assert_error_with_msg(
-            move_file(src_dir.path(), "/dest/does/not/matter", false),
-            "not a file",
+            move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx(false)).map(|(msg, _)| msg),
+            "already exists",
         );
+        let (_, stats) = move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx(true)).unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert_eq!(stats.io_bytes, data.len() as u64 - 5000);
+
+        // A partial dest whose tail doesn't match is copied over from the start.
+        let mut partial = data[..5000].to_vec();
+        partial[4999] ^= 1;
+        fs::write(&dest_path, &partial).unwrap();
+        let (_, stats) = move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx(true)).unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert_eq!(stats.io_bytes, data.len() as u64);
     }
 
     #[test]
-    fn move_file_fails_when_cant_create_intermediate_directories() {
+    fn resume_offset_compares_head_samples_and_nonzero_tail() {
         let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        create_temp_file(work_dir.path(), "b/c/d", "");
-        let dest_path = work_dir
-            .path()
-            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+        let mut data: Vec<u8> = (0..5u32 << 20).map(|i| (i % 251) as u8 | 1).collect();
+        data[2 << 20..4 << 20].fill(0);
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let partial_path = work_dir.path().join("b");
 
-        assert_error_with_msg(move_file(&src_path, &dest_path, false), "Not a directory");
-        assert_file_not_moved(&src_path, &dest_path);
+        // A zeroed file matching the source's zeroed run at its end.
+        fs::write(&partial_path, vec![0; 4 << 20]).unwrap();
+        assert_eq!(resume_offset(&src_path, &partial_path), None);
+
+        for len in [5000, 2 << 20, (4 << 20) + 1] {
+            fs::write(&partial_path, &data[..len]).unwrap();
+            assert_eq!(resume_offset(&src_path, &partial_path), Some(len as u64));
+        }
+        // Even a genuine copy is started over if it stopped in the zeroed run.
+        fs::write(&partial_path, &data[..3 << 20]).unwrap();
+        assert_eq!(resume_offset(&src_path, &partial_path), None);
+
+        // Differing in the first block, or only in a sampled one.
+        for pos in [10, 2 << 20] {
+            let mut partial = data[..(4 << 20) + 1].to_vec();
+            partial[pos] ^= 1;
+            fs::write(&partial_path, &partial).unwrap();
+            assert_eq!(resume_offset(&src_path, &partial_path), None, "{pos}");
+        }
+
+        // The reported case: `-f --resume` copies everything instead.
+        fs::write(&partial_path, vec![0; 4 << 20]).unwrap();
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            reflink: ReflinkMode::Never,
+            resume: true,
+            force: true,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        move_or_copy(&src_path, &partial_path, |_| {}, false, &ctx).unwrap();
+        assert_eq!(fs::read(&partial_path).unwrap(), data);
     }
 
     #[test]
-    fn copy_file_fails_when_cant_create_intermediate_directories() {
+    fn copy_direct_handles_unaligned_tail() {
         let work_dir = tempdir().unwrap();
-        let src_content = "This is a test file";
-        let src_path = create_temp_file(work_dir.path(), "a", src_content);
-        create_temp_file(work_dir.path(), "b/c/d", "");
-        let dest_path = work_dir
-            .path()
-            .join("b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z");
+        // Spans several cache-drop windows and ends off a block boundary.
+        let data: Vec<u8> = (0..(20 << 20) + 123)
+            .map(|i: u32| (i % 251) as u8)
+            .collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
 
-        assert_error_with_msg(copy_file(&src_path, &dest_path, false), "Not a directory");
-        assert_file_not_moved(&src_path, &dest_path);
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            direct: true,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let (_, stats) = move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx).unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert_eq!(stats.io_bytes, data.len() as u64);
+        assert_eq!(stats.kernel_bytes, 0);
+    }
+
+    #[test]
+    fn copy_with_single_small_buffer() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..(1 << 20) + 5).map(|i: u32| (i % 251) as u8).collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            // Forces the userspace pipeline.
+            sparse: SparseMode::Always,
+            buffer_size: 1000,
+            buffer_count: 1,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx).unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+    }
+
+    #[test]
+    fn pipelined_copy_sizes_ring_to_data() {
+        let work_dir = tempdir().unwrap();
+        let src_path = work_dir.path().join("a");
+        let dest_path = work_dir.path().join("b");
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            buffer_size: 4096,
+            buffer_count: 4,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+
+        // Inline, then a ring of 2, then the full ring.
+        for len in [1, 4096, 4097, 5 * 4096 + 3] {
+            let data: Vec<u8> = (0..len).map(|i: u32| (i % 251) as u8 | 1).collect();
+            fs::write(&src_path, &data).unwrap();
+            let reader = fs::File::open(&src_path).unwrap();
+            let writer = fs::File::create(&dest_path).unwrap();
+            let last = std::cell::Cell::new(0);
+            let written = pipelined_copy(&reader, &writer, &[(0, len.into())], &ctx, &mut |pos| {
+                last.set(pos)
+            })
+            .unwrap();
+            assert_eq!(written, u64::from(len));
+            assert_eq!(last.get(), u64::from(len));
+            assert_eq!(fs::read(&dest_path).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn copy_in_parallel_streams() {
+        let work_dir = tempdir().unwrap();
+        let mut data: Vec<u8> = (0..(4 << 20) + 5)
+            .map(|i: u32| (i % 251) as u8 | 1)
+            .collect();
+        // A zeroed run that stays a hole in `dest`.
+        data[1 << 20..2 << 20].fill(0);
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            sparse: SparseMode::Always,
+            buffer_size: 64 << 10,
+            streams: 4,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let last = std::cell::Cell::new(0);
+        let (_, stats) =
+            move_or_copy(&src_path, &dest_path, |pos| last.set(pos), false, &ctx).unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert_eq!(last.get(), data.len() as u64);
+        assert_eq!(stats.io_bytes, data.len() as u64 - (1 << 20));
+        assert_eq!(stats.kernel_bytes, 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn copy_in_parallel_streams_in_kernel() {
+        let work_dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..(4 << 20) + 5)
+            .map(|i: u32| (i % 251) as u8 | 1)
+            .collect();
+        let src_path = work_dir.path().join("a");
+        fs::write(&src_path, &data).unwrap();
+        let dest_path = work_dir.path().join("b");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        let ctx = Ctx {
+            buffer_size: 64 << 10,
+            streams: 4,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        let last = std::cell::Cell::new(0);
+        let (_, stats) =
+            move_or_copy(&src_path, &dest_path, |pos| last.set(pos), false, &ctx).unwrap();
+        assert_eq!(fs::read(&dest_path).unwrap(), data);
+        assert_eq!(last.get(), data.len() as u64);
+        assert_eq!(stats.io_bytes, data.len() as u64);
+        assert_eq!(stats.kernel_bytes, data.len() as u64);
+    }
+
+    /// Compares a serial userspace copy (one buffer) with the pipelined one,
+    /// and both with a default copy, between two tmpfs files. Run with
+    /// `just bench`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_pipelined_copy() {
+        const LEN: u64 = 512 << 20;
+        let work_dir = tempfile::tempdir_in("/dev/shm").unwrap();
+        let src_path = work_dir.path().join("src");
+        let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8 | 1).collect();
+        fs::write(&src_path, &data).unwrap();
+        drop(data);
+        let dest_path = work_dir.path().join("dest");
+        let report = |what: &str, elapsed: std::time::Duration| {
+            println!("{what}: {elapsed:.2?}{}", crate::human_speed(LEN, elapsed));
+        };
+
+        let mp = hidden_multi_progress();
+        let ctrlc = AtomicBool::new(false);
+        // The userspace pipeline on its own, bypassing `copy_file_range`.
+        for buffer_count in [1, 2, 4, 8] {
+            let ctx = Ctx {
+                buffer_count,
+                ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+            };
+            let reader = fs::File::open(&src_path).unwrap();
+            let writer = fs::File::create(&dest_path).unwrap();
+            let timer = std::time::Instant::now();
+            pipelined_copy(&reader, &writer, &[(0, LEN)], &ctx, &mut |_| {}).unwrap();
+            report(&format!("{buffer_count} buffer(s)"), timer.elapsed());
+            fs::remove_file(&dest_path).unwrap();
+        }
+
+        // tmpfs can't clone, so this goes through `copy_file_range`.
+        let ctx = test_ctx(MoveOrCopy::Copy, &mp, &ctrlc);
+        let timer = std::time::Instant::now();
+        move_or_copy(&src_path, &dest_path, |_| {}, false, &ctx).unwrap();
+        report("default copy", timer.elapsed());
     }
 }
@@ -8,6 +8,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 
 mod dir;
 mod file;
+mod meta;
 
 #[derive(Debug, Clone, Copy)]
 pub enum SourceKind {
@@ -53,53 +54,230 @@ impl MoveOrCopy {
 
 #[derive(Debug, Clone, Copy, Default)]
 pub(crate) struct TransferStats {
+    /// Data bytes actually read and written; holes skipped in sparse files don't count.
     pub io_bytes: u64,
+    /// Full length of the files behind `io_bytes`, holes included.
+    pub apparent_bytes: u64,
+    /// Part of `io_bytes` copied inside the kernel with `copy_file_range`.
+    pub kernel_bytes: u64,
     pub fast_path_file_count: u64,
+    /// Part of `fast_path_file_count` cloned with a reflink rather than renamed.
+    pub reflinked_file_count: u64,
     pub fast_path_dir_count: u64,
 }
 
 impl TransferStats {
-    /// Format fast-path counts as e.g. "1 directory", "3 files", "1 directory + 3 files".
-    /// Returns `None` when both counts are zero.
+    /// Fast-path counts grouped by how they were done, e.g.
+    /// `[("renamed", "1 directory + 3 files"), ("reflinked", "2 files")]`.
     #[must_use]
-    fn fast_path_summary(self) -> Option<String> {
-        if self.fast_path_file_count == 0 && self.fast_path_dir_count == 0 {
-            return None;
-        }
-        let mut parts = Vec::new();
-        if self.fast_path_dir_count > 0 {
-            let noun = if self.fast_path_dir_count == 1 {
-                "directory"
-            } else {
-                "directories"
-            };
-            parts.push(format!("{} {noun}", self.fast_path_dir_count));
-        }
-        if self.fast_path_file_count > 0 {
-            let noun = if self.fast_path_file_count == 1 {
-                "file"
-            } else {
-                "files"
-            };
-            parts.push(format!("{} {noun}", self.fast_path_file_count));
-        }
-        Some(parts.join(" + "))
+    fn fast_path_summary(self) -> Vec<(&'static str, String)> {
+        let renamed_files = self.fast_path_file_count - self.reflinked_file_count;
+        [
+            ("renamed", self.fast_path_dir_count, renamed_files),
+            ("reflinked", 0, self.reflinked_file_count),
+        ]
+        .into_iter()
+        .filter_map(|(label, dirs, files)| count_summary(dirs, files).map(|s| (label, s)))
+        .collect()
+    }
+}
+
+/// Format counts as e.g. "1 directory", "3 files", "1 directory + 3 files".
+/// Returns `None` when both counts are zero.
+#[must_use]
+fn count_summary(dirs: u64, files: u64) -> Option<String> {
+    if files == 0 && dirs == 0 {
+        return None;
+    }
+    let mut parts = Vec::new();
+    if dirs > 0 {
+        let noun = if dirs == 1 {
+            "directory"
+        } else {
+            "directories"
+        };
+        parts.push(format!("{dirs} {noun}"));
     }
+    if files > 0 {
+        let noun = if files == 1 { "file" } else { "files" };
+        parts.push(format!("{files} {noun}"));
+    }
+    Some(parts.join(" + "))
 }
 
 impl std::ops::AddAssign for TransferStats {
     fn add_assign(&mut self, rhs: Self) {
         self.io_bytes += rhs.io_bytes;
+        self.apparent_bytes += rhs.apparent_bytes;
+        self.kernel_bytes += rhs.kernel_bytes;
         self.fast_path_file_count += rhs.fast_path_file_count;
+        self.reflinked_file_count += rhs.reflinked_file_count;
         self.fast_path_dir_count += rhs.fast_path_dir_count;
     }
 }
 
+/// A destination entry that would block a move or copy partway through.
+#[derive(Debug)]
+pub(crate) enum Conflict {
+    FileOverFile(PathBuf, PathBuf),
+    FileOverDir(PathBuf, PathBuf),
+    DirOverFile(PathBuf, PathBuf),
+    /// A source entry whose destination is the entry itself, e.g. through a
+    /// hard link or a symlinked directory.
+    SameFile(PathBuf, PathBuf),
+    /// A followed symlink back to a directory it is inside of.
+    SymlinkLoop(PathBuf),
+    /// Two sources in one batch that would land on the same path.
+    SameDest(PathBuf, PathBuf, PathBuf),
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FileOverFile(src, dest) => write!(
+                f,
+                "file '{}' -> existing file '{}'",
+                src.display(),
+                dest.display()
+            ),
+            Self::FileOverDir(src, dest) => write!(
+                f,
+                "file '{}' -> existing directory '{}'",
+                src.display(),
+                dest.display()
+            ),
+            Self::DirOverFile(src, dest) => write!(
+                f,
+                "directory '{}' -> existing file '{}'",
+                src.display(),
+                dest.display()
+            ),
+            Self::SameFile(src, dest) => write!(
+                f,
+                "'{}' and '{}' are the same file",
+                src.display(),
+                dest.display()
+            ),
+            Self::SymlinkLoop(src) => write!(
+                f,
+                "symlink loop: '{}' leads back to a directory it is inside of",
+                src.display()
+            ),
+            Self::SameDest(first, src, dest) => write!(
+                f,
+                "'{}' and '{}' -> same destination '{}'",
+                first.display(),
+                src.display(),
+                dest.display()
+            ),
+        }
+    }
+}
+
+/// Extended attribute namespaces that can be left out of a copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum XattrNamespace {
+    /// `user.*` attributes
+    User,
+    /// POSIX ACLs (`system.posix_acl_access` and `system.posix_acl_default`)
+    Acl,
+    /// SELinux labels, file capabilities and other `security.*` attributes
+    Security,
+    /// `trusted.*` attributes (only readable by root)
+    Trusted,
+}
+
+/// Whether copies may, or must, be copy-on-write clones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReflinkMode {
+    /// Clone when the filesystem supports it, copy data otherwise
+    #[default]
+    Auto,
+    /// Clone, and fail when that isn't possible
+    Always,
+    /// Always copy data, so nothing is shared with the source
+    Never,
+}
+
+/// Whether the destination is built from links to the source files rather
+/// than copies of them. Directories are still created and merged as usual.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Copy file data
+    #[default]
+    Off,
+    /// Hard-link each file to its source (`--link`). With `copy_fallback`,
+    /// files on another device are copied instead of failing.
+    Hard { copy_fallback: bool },
+    /// Symlink each file to its source (`--symlink`), with a target relative
+    /// to the link's directory if `relative` is set, absolute otherwise.
+    Symbolic { relative: bool },
+}
+
+/// How to handle holes when file data has to be copied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SparseMode {
+    /// Keep the holes the source file already has
+    #[default]
+    Auto,
+    /// Also turn blocks of zeros into holes
+    Always,
+    /// Write every byte, filling in holes
+    Never,
+}
+
+/// When to follow symlinks in sources instead of moving/copying the links themselves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dereference {
+    /// Never follow symlinks (`-P`)
+    #[default]
+    Never,
+    /// Follow symlinks named on the command line only (`-H`)
+    CommandLine,
+    /// Follow all symlinks (`-L`)
+    Always,
+}
+
+impl Dereference {
+    /// Whether to follow a symlink named on the command line.
+    #[must_use]
+    pub(crate) const fn top_level(self) -> bool {
+        !matches!(self, Self::Never)
+    }
+
+    /// Whether to follow a symlink found while walking a source directory.
+    #[must_use]
+    pub(crate) const fn nested(self) -> bool {
+        matches!(self, Self::Always)
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct Ctx<'a> {
     pub moc: MoveOrCopy,
     pub force: bool,
     pub dry_run: bool,
     pub batch_size: usize,
+    pub skip_xattrs: &'a [XattrNamespace],
+    pub deref: Dereference,
+    pub sparse: SparseMode,
+    pub reflink: ReflinkMode,
+    pub link: LinkMode,
+    pub direct: bool,
+    /// Flush copied files and their directory entries to disk before a
+    /// transfer is reported done or its source removed.
+    pub sync: bool,
+    /// Continue interrupted copies from a part file, or with `force` from a
+    /// partial destination, and keep part files around when a copy fails.
+    pub resume: bool,
+    /// Size of each buffer in a userspace copy, rounded up to a multiple of 4 KiB.
+    pub buffer_size: usize,
+    /// Number of buffers shared between the reading and writing sides of a copy.
+    pub buffer_count: usize,
+    /// Number of files within a directory to transfer at once.
+    pub jobs: usize,
+    /// Number of concurrent streams to copy a single large file with.
+    pub streams: usize,
     pub mp: &'a indicatif::MultiProgress,
     pub ctrlc: &'a AtomicBool,
 }
@@ -154,27 +332,48 @@ impl Ctx<'_> {
             String::new()
         };
 
-        if stats.io_bytes > 0 {
-            let fast_suffix = if let Some(summary) = &fast_parts {
-                let label = match self.moc {
-                    MoveOrCopy::Move => "renamed",
-                    MoveOrCopy::Copy => "reflinked",
-                };
-                format!(", {summary} {label}")
-            } else {
+        // A fully sparse file reads no data but still has a size worth showing.
+        if stats.io_bytes > 0 || stats.apparent_bytes > 0 {
+            let fast_suffix: String = fast_parts
+                .iter()
+                .map(|(label, summary)| format!(", {summary} {label}"))
+                .collect();
+            let mut notes = Vec::new();
+            if stats.apparent_bytes != stats.io_bytes {
+                notes.push(format!(
+                    "{} apparent",
+                    indicatif::HumanBytes(stats.apparent_bytes)
+                ));
+            }
+            if stats.kernel_bytes > 0 {
+                notes.push(format!(
+                    "{} kernel-copied",
+                    indicatif::HumanBytes(stats.kernel_bytes)
+                ));
+            }
+            let notes = if notes.is_empty() {
                 String::new()
+            } else {
+                format!(" ({})", notes.join(", "))
             };
             format!(
-                "{verb} {}{duration}{}{fast_suffix}",
+                "{verb} {}{notes}{duration}{}{fast_suffix}",
                 indicatif::HumanBytes(stats.io_bytes),
-                human_speed(stats.io_bytes, elapsed),
+                if stats.io_bytes > 0 {
+                    human_speed(stats.io_bytes, elapsed)
+                } else {
+                    String::new()
+                },
             )
-        } else if let Some(summary) = &fast_parts {
-            let label = match self.moc {
-                MoveOrCopy::Move => "Renamed",
-                MoveOrCopy::Copy => "Reflinked",
-            };
-            format!("{label} {summary}{duration}")
+        } else if !fast_parts.is_empty() {
+            let summary = fast_parts
+                .iter()
+                .map(|(label, summary)| format!("{label} {summary}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut chars = summary.chars();
+            let first = chars.next().unwrap().to_uppercase();
+            format!("{first}{}{duration}", chars.as_str())
         } else {
             format!("{verb}{duration}")
         }
@@ -226,19 +425,22 @@ pub fn init_logging(level_filter: LevelFilter) -> indicatif::MultiProgress {
     mp
 }
 
-fn validate_sources(srcs: &[&Path], dest: &Path) -> anyhow::Result<SourceKind> {
+fn validate_sources(srcs: &[&Path], dest: &Path, ctx: &Ctx) -> anyhow::Result<SourceKind> {
     let mut all_files = true;
     let mut all_dirs = true;
     for src in srcs {
-        if src.is_file() {
-            all_dirs = false;
-        } else if src.is_dir() {
-            all_files = false;
-        } else {
-            bail!(
+        // Unfollowed symlinks and special files are recreated rather than
+        // read, so they count as files.
+        match metadata(src, ctx.deref.top_level()) {
+            Ok(m) if m.is_dir() => {
+                all_files = false;
+                dir::ensure_not_inside(src, dest)?;
+            }
+            Ok(_) => all_dirs = false,
+            Err(_) => bail!(
                 "Source path '{}' is neither a file nor directory.",
                 src.display()
-            );
+            ),
         }
     }
 
@@ -249,7 +451,7 @@ fn validate_sources(srcs: &[&Path], dest: &Path) -> anyhow::Result<SourceKind> {
         );
         if !dest.is_dir() {
             if all_dirs || dest.to_string_lossy().ends_with('/') {
-                std::fs::create_dir_all(dest)?;
+                file::create_dirs(dest, ctx.sync)?;
             } else {
                 bail!(
                     "When there are multiple file sources, the destination must be a directory or end with '/'."
@@ -265,6 +467,69 @@ fn validate_sources(srcs: &[&Path], dest: &Path) -> anyhow::Result<SourceKind> {
     })
 }
 
+/// Scan every source against the destination before anything is mutated, so a
+/// conflict deep in the tree can't leave a source half-moved across two trees.
+fn preflight(srcs: &[&Path], dest: &Path, kind: SourceKind, ctx: &Ctx) -> anyhow::Result<()> {
+    let mut conflicts = Vec::new();
+    for src in srcs {
+        let target = match kind {
+            SourceKind::File => file::resolve_dest(src, dest)?,
+            SourceKind::Dir => dest.to_path_buf(),
+        };
+        conflicts.extend(dir::find_conflicts(src, &target, ctx.force, ctx.deref));
+    }
+    // Sources can also collide with each other, which `-f` doesn't cover:
+    // the later one would overwrite, or fail on, what the earlier one put there.
+    if srcs.len() > 1 {
+        let mut planned: std::collections::HashMap<PathBuf, (PathBuf, bool)> =
+            std::collections::HashMap::new();
+        for src in srcs {
+            let dests = match kind {
+                SourceKind::File => {
+                    vec![(file::resolve_dest(src, dest)?, src.to_path_buf(), false)]
+                }
+                SourceKind::Dir => dir::planned_dests(src, dest, ctx.deref),
+            };
+            for (target, src_entry, is_dir) in dests {
+                match planned.get(&target) {
+                    Some((_, true)) if is_dir => {}
+                    Some((first, _)) => {
+                        conflicts.push(Conflict::SameDest(first.clone(), src_entry, target));
+                    }
+                    None => {
+                        planned.insert(target, (src_entry, is_dir));
+                    }
+                }
+            }
+        }
+    }
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    let verb = match ctx.moc {
+        MoveOrCopy::Move => "moved",
+        MoveOrCopy::Copy => "copied",
+    };
+    let list = conflicts
+        .iter()
+        .map(|c| format!("\n  {c}"))
+        .collect::<String>();
+    let hint = if conflicts
+        .iter()
+        .any(|c| matches!(c, Conflict::FileOverFile(..)))
+    {
+        "\n(use -f to overwrite existing files)"
+    } else {
+        ""
+    };
+    bail!(
+        "Found {} conflict{} in destination, nothing was {verb}:{list}{hint}",
+        conflicts.len(),
+        if conflicts.len() == 1 { "" } else { "s" },
+    )
+}
+
 fn process_source(
     src: &Path,
     dest: &Path,
@@ -272,17 +537,22 @@ fn process_source(
     base: u64,
     sized: bool,
     ctx: &Ctx,
+    links: &mut dir::LinkMap,
 ) -> anyhow::Result<(String, TransferStats)> {
     let progress = move |bytes: u64| {
         if sized {
             batch_pb.set_position(base + bytes);
         }
     };
-    if src.is_file() {
-        file::move_or_copy(src, dest, progress, ctx)
-    } else {
-        dir::merge_or_copy(src, dest, progress, ctx)
+    let follow = ctx.deref.top_level();
+    if is_dir(src, follow) {
+        return dir::merge_or_copy(src, dest, progress, ctx, links);
     }
+
+    let target = file::resolve_dest(src, dest)?;
+    links.link_or_transfer(src, &target, follow, ctx, |_| {
+        file::move_or_copy(src, dest, progress, follow, ctx)
+    })
 }
 
 /// # Errors
@@ -306,11 +576,17 @@ pub fn run_batch<Src: AsRef<Path>, Srcs: AsRef<[Src]>, Dest: AsRef<Path>>(
         ctx.moc,
     );
 
-    let kind = validate_sources(&srcs, dest)?;
+    // Resuming copies the rest of the data, which `always` forbids.
+    ensure!(
+        !(ctx.resume && ctx.reflink == ReflinkMode::Always),
+        "--resume can't be combined with --reflink=always"
+    );
+    let kind = validate_sources(&srcs, dest, ctx)?;
+    preflight(&srcs, dest, kind, ctx)?;
 
     if ctx.dry_run {
         for src in srcs {
-            let action = match (ctx.moc, src.is_dir()) {
+            let action = match (ctx.moc, is_dir(src, ctx.deref.top_level())) {
                 (MoveOrCopy::Move, true) => "merge",
                 (MoveOrCopy::Move, false) => "move",
                 (MoveOrCopy::Copy, _) => "copy",
@@ -324,9 +600,12 @@ pub fn run_batch<Src: AsRef<Path>, Srcs: AsRef<[Src]>, Dest: AsRef<Path>>(
     let sizes: Vec<u64> = srcs
         .iter()
         .map(|s| {
-            let skip =
-                matches!(ctx.moc, MoveOrCopy::Move) && s.is_dir() && dir::same_device(s, dest);
-            if skip { 0 } else { source_size(s) }
+            // A followed symlink to a directory is copied, never renamed.
+            let skip = matches!(ctx.moc, MoveOrCopy::Move)
+                && is_dir(s, false)
+                && !ctx.deref.nested()
+                && dir::same_device(s, dest);
+            if skip { 0 } else { source_size(s, ctx.deref) }
         })
         .collect();
     let batch_pb = if n > 1 {
@@ -339,6 +618,7 @@ pub fn run_batch<Src: AsRef<Path>, Srcs: AsRef<[Src]>, Dest: AsRef<Path>>(
     let batch_timer = std::time::Instant::now();
     let mut cumulative: u64 = 0;
     let mut batch_stats = TransferStats::default();
+    let mut links = dir::LinkMap::new();
     for (i, src) in srcs.iter().enumerate() {
         if ctx.ctrlc.load(Ordering::Relaxed) {
             log::error!(
@@ -360,8 +640,24 @@ pub fn run_batch<Src: AsRef<Path>, Srcs: AsRef<[Src]>, Dest: AsRef<Path>>(
             .unwrap_or_default();
         batch_pb.set_message(format!("[{}/{}]{up_next}", i + 1, n));
 
-        let (msg, stats) = process_source(src, dest, &batch_pb, cumulative, sizes[i] > 0, ctx)
-            .with_context(|| message_with_arrow(src, dest, ctx.moc, false))?;
+        let (msg, stats) = match process_source(
+            src,
+            dest,
+            &batch_pb,
+            cumulative,
+            sizes[i] > 0,
+            ctx,
+            &mut links,
+        ) {
+            Err(e) if is_cancelled(&e) => {
+                log::error!(
+                    "{FAIL_MARK} Cancelled: {}",
+                    message_with_arrow(src, dest, ctx.moc, true)
+                );
+                std::process::exit(130);
+            }
+            result => result.with_context(|| message_with_arrow(src, dest, ctx.moc, false))?,
+        };
         batch_stats += stats;
 
         cumulative += sizes[i];
@@ -379,6 +675,28 @@ pub fn run_batch<Src: AsRef<Path>, Srcs: AsRef<[Src]>, Dest: AsRef<Path>>(
     Ok(String::new())
 }
 
+/// Error for a transfer stopped partway through by Ctrl-C.
+#[derive(Debug)]
+pub(crate) struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Whether `err` comes from a transfer stopped by Ctrl-C.
+pub(crate) fn is_cancelled(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.is::<Cancelled>()
+            || e.downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::get_ref)
+                .is_some_and(|e| e.is::<Cancelled>())
+    })
+}
+
 /// # Errors
 ///
 /// Will return `Err` if can not register Ctrl-C handler.
@@ -389,12 +707,13 @@ pub fn ctrlc_flag() -> anyhow::Result<Arc<AtomicBool>> {
     ctrlc::set_handler(move || {
         if already_pressed.swap(true, Ordering::Relaxed) {
             log::warn!("{FAIL_MARK} Ctrl-C again, force exiting...");
+            file::remove_part_files();
             // Use _exit() to terminate immediately without running atexit handlers
             // or destructors, which can deadlock (e.g. indicatif's render thread).
             unsafe { libc::_exit(130) };
         }
         log::warn!(
-            "{FAIL_MARK} Ctrl-C detected, finishing current file... (press again to force exit)"
+            "{FAIL_MARK} Ctrl-C detected, cancelling current file... (press again to force exit)"
         );
         flag_clone.store(true, Ordering::Relaxed);
     })?;
@@ -426,14 +745,55 @@ fn item_progress_bar<Src: AsRef<Path>, Dest: AsRef<Path>>(
     bytes_progress_bar(size, color, moc).with_message(message_with_arrow(src, dest, moc, true))
 }
 
-fn source_size(src: &Path) -> u64 {
-    if src.is_file() {
-        std::fs::metadata(src).map(|m| m.len()).unwrap_or(0)
+fn source_size(src: &Path, deref: Dereference) -> u64 {
+    if is_dir(src, deref.top_level()) {
+        dir::collect_total_size(src, deref.nested())
+    } else {
+        file_size(src, deref.top_level())
+    }
+}
+
+/// Parse a byte count such as `65536`, `512K`, `4M` or `1G` (binary units).
+///
+/// # Errors
+///
+/// Returns an error if `s` isn't a positive count or overflows `usize`.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    num.parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_shl(shift).filter(|&b| b > 0 && b >> shift == n))
+        .ok_or_else(|| format!("invalid size '{s}'"))
+}
+
+/// Stat `path`, following a symlink only if `follow` is set.
+pub(crate) fn metadata(path: &Path, follow: bool) -> std::io::Result<std::fs::Metadata> {
+    if follow {
+        std::fs::metadata(path)
     } else {
-        dir::collect_total_size(src)
+        std::fs::symlink_metadata(path)
     }
 }
 
+/// Whether `path` is a directory, or (with `follow`) a symlink to one.
+pub(crate) fn is_dir(path: &Path, follow: bool) -> bool {
+    metadata(path, follow).is_ok_and(|m| m.is_dir())
+}
+
+/// Bytes to transfer for a non-directory entry: its length if it's a regular
+/// file, 0 for unfollowed symlinks (recreated, not read) or on error.
+pub(crate) fn file_size(path: &Path, follow: bool) -> u64 {
+    metadata(path, follow)
+        .ok()
+        .filter(std::fs::Metadata::is_file)
+        .map_or(0, |m| m.len())
+}
+
 pub const FAIL_MARK: &str = "✗";
 
 pub(crate) fn human_speed(bytes: u64, elapsed: std::time::Duration) -> String {
@@ -551,10 +911,56 @@ pub(crate) mod tests {
     use std::path::{Path, PathBuf};
     use tempfile::tempdir;
 
+    /// End a test early when the environment can't exercise it (not root, no
+    /// hole support, ...), printing why past libtest's output capture so the
+    /// skip shows up in the test log instead of passing silently.
+    macro_rules! skip_unless {
+        ($cond:expr, $why:expr) => {
+            if !$cond {
+                use std::io::Write as _;
+                let test = std::thread::current().name().unwrap_or("?").to_owned();
+                let _ = writeln!(std::io::stderr(), "SKIPPED {test}: {}", $why);
+                return;
+            }
+        };
+    }
+    pub(crate) use skip_unless;
+
+    pub(crate) fn is_root() -> bool {
+        (unsafe { libc::geteuid() }) == 0
+    }
+
     pub(crate) fn noop_ctrlc() -> Arc<AtomicBool> {
         Arc::new(AtomicBool::new(false))
     }
 
+    pub(crate) fn test_ctx<'a>(
+        moc: MoveOrCopy,
+        mp: &'a indicatif::MultiProgress,
+        ctrlc: &'a AtomicBool,
+    ) -> Ctx<'a> {
+        Ctx {
+            moc,
+            force: false,
+            dry_run: false,
+            batch_size: 1,
+            skip_xattrs: &[],
+            deref: Dereference::Never,
+            sparse: SparseMode::Auto,
+            reflink: ReflinkMode::Auto,
+            link: LinkMode::Off,
+            direct: false,
+            sync: false,
+            resume: false,
+            buffer_size: 1 << 20,
+            buffer_count: 4,
+            jobs: 1,
+            streams: 1,
+            mp,
+            ctrlc,
+        }
+    }
+
     pub(crate) fn hidden_multi_progress() -> indicatif::MultiProgress {
         indicatif::MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden())
     }
@@ -651,16 +1057,57 @@ pub(crate) mod tests {
         let mp = hidden_multi_progress();
         let ctrlc = noop_ctrlc();
         let ctx = Ctx {
-            moc,
             force,
-            dry_run: false,
             batch_size: srcs.as_ref().len(),
-            mp: &mp,
-            ctrlc: &ctrlc,
+            ..test_ctx(moc, &mp, &ctrlc)
         };
         run_batch(srcs, dest, &ctx)
     }
 
+    #[test]
+    fn parse_size_accepts_binary_suffixes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("4m"), Ok(4 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("99999999999999999999G").is_err());
+    }
+
+    #[test]
+    fn done_stats_splits_renamed_and_reflinked() {
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = test_ctx(MoveOrCopy::Move, &mp, &ctrlc);
+        let stats = TransferStats {
+            fast_path_file_count: 3,
+            reflinked_file_count: 2,
+            fast_path_dir_count: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            ctx.done_stats(SourceKind::Dir, stats, std::time::Duration::ZERO),
+            "Renamed 1 directory + 1 file, reflinked 2 files"
+        );
+    }
+
+    #[test]
+    fn done_stats_shows_apparent_size_of_fully_sparse_copy() {
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = test_ctx(MoveOrCopy::Copy, &mp, &ctrlc);
+        let stats = TransferStats {
+            apparent_bytes: 8 << 20,
+            ..Default::default()
+        };
+        assert_eq!(
+            ctx.done_stats(SourceKind::File, stats, std::time::Duration::from_secs(1)),
+            "Copied 0 B (8.00 MiB apparent) in 1 second"
+        );
+    }
+
     #[test]
     fn move_file_to_new_dest() {
         let work_dir = tempdir().unwrap();
@@ -854,12 +1301,8 @@ pub(crate) mod tests {
         let mp = hidden_multi_progress();
         let ctrlc = noop_ctrlc();
         let ctx = Ctx {
-            moc: MoveOrCopy::Move,
-            force: false,
             dry_run: true,
-            batch_size: 1,
-            mp: &mp,
-            ctrlc: &ctrlc,
+            ..test_ctx(MoveOrCopy::Move, &mp, &ctrlc)
         };
         run_batch([&src_path], &dest_path, &ctx).unwrap();
 
@@ -1024,4 +1467,272 @@ pub(crate) mod tests {
             "{ foo -> bar }/common.txt"
         );
     }
+
+    #[test]
+    fn copy_directory_into_own_subdirectory_fails() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "file1", "content");
+        let dest_dir = src_dir.path().join("backup");
+
+        assert_error_with_msg(
+            _run_batch([&src_dir.path()], &dest_dir, MoveOrCopy::Copy, false),
+            "is inside source directory",
+        );
+        assert!(!dest_dir.exists());
+    }
+
+    #[test]
+    fn move_directory_into_own_subdirectory_fails() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "sub/file1", "content");
+        let dest_dir = src_dir.path().join("sub");
+
+        assert_error_with_msg(
+            _run_batch([&src_dir.path()], &dest_dir, MoveOrCopy::Move, false),
+            "is inside source directory",
+        );
+        assert!(src_dir.path().join("sub/file1").exists());
+    }
+
+    #[test]
+    fn batch_fails_when_any_source_contains_dest() {
+        let work_dir = tempdir().unwrap();
+        let other_dir = work_dir.path().join("other");
+        let outer_dir = work_dir.path().join("outer");
+        create_temp_file(&other_dir, "file1", "content1");
+        create_temp_file(&outer_dir, "file2", "content2");
+        let dest_dir = outer_dir.join("dest");
+
+        assert_error_with_msg(
+            _run_batch([&other_dir, &outer_dir], &dest_dir, MoveOrCopy::Move, false),
+            "is inside source directory",
+        );
+        assert!(other_dir.join("file1").exists());
+        assert!(outer_dir.join("file2").exists());
+        assert!(!dest_dir.exists());
+    }
+
+    #[test]
+    fn merge_aborts_before_moving_anything_when_conflicts_exist() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "a_first", "src");
+        create_temp_file(src_dir.path(), "b_dir_vs_file/inner", "src");
+        create_temp_file(src_dir.path(), "c_file_vs_dir", "src");
+        create_temp_file(src_dir.path(), "d_both", "src");
+
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "b_dir_vs_file", "dest");
+        create_temp_file(dest_dir.path(), "c_file_vs_dir/inner", "dest");
+        create_temp_file(dest_dir.path(), "d_both", "dest");
+
+        let result = _run_batch([&src_dir], &dest_dir, MoveOrCopy::Move, false);
+        let err_msg = format!("{:#}", result.unwrap_err());
+        assert!(err_msg.contains("Found 3 conflicts"), "{err_msg}");
+        assert!(
+            err_msg.contains("b_dir_vs_file' -> existing file"),
+            "{err_msg}"
+        );
+        assert!(
+            err_msg.contains("c_file_vs_dir' -> existing directory"),
+            "{err_msg}"
+        );
+        assert!(err_msg.contains("d_both' -> existing file"), "{err_msg}");
+        assert!(err_msg.contains("use -f to overwrite"), "{err_msg}");
+
+        // Nothing was moved, not even entries sorted before the first conflict
+        assert_file_not_moved(
+            src_dir.path().join("a_first"),
+            dest_dir.path().join("a_first"),
+        );
+    }
+
+    #[test]
+    fn merge_with_force_still_aborts_on_type_conflicts() {
+        let src_dir = tempdir().unwrap();
+        create_temp_file(src_dir.path(), "a_first", "src");
+        create_temp_file(src_dir.path(), "b_both", "src");
+        create_temp_file(src_dir.path(), "c_file_vs_dir", "src");
+
+        let dest_dir = tempdir().unwrap();
+        create_temp_file(dest_dir.path(), "b_both", "dest");
+        create_temp_file(dest_dir.path(), "c_file_vs_dir/inner", "dest");
+
+        assert_error_with_msg(
+            _run_batch([&src_dir], &dest_dir, MoveOrCopy::Copy, true),
+            "Found 1 conflict in destination, nothing was copied",
+        );
+        assert!(!dest_dir.path().join("a_first").exists());
+    }
+
+    #[test]
+    fn batch_aborts_before_first_source_when_later_source_conflicts() {
+        let work_dir = tempdir().unwrap();
+        let src_paths = vec![
+            create_temp_file(work_dir.path(), "a", "content_a"),
+            create_temp_file(work_dir.path(), "b", "content_b"),
+        ];
+        let dest_dir = work_dir.path().join("dest");
+        create_temp_file(&dest_dir, "b", "existing");
+
+        assert_error_with_msg(
+            _run_batch(&src_paths, &dest_dir, MoveOrCopy::Move, false),
+            "Found 1 conflict",
+        );
+        assert_file_not_moved(&src_paths[0], dest_dir.join("a"));
+    }
+
+    #[test]
+    fn batch_aborts_when_two_sources_share_a_destination() {
+        let src_dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        create_temp_file(src_dirs[0].path(), "only_first", "first");
+        create_temp_file(src_dirs[0].path(), "nested/both", "first");
+        create_temp_file(src_dirs[1].path(), "nested/both", "second");
+        let dest_dir = tempdir().unwrap();
+
+        // Even with -f, the second source would clobber the first one's file.
+        assert_error_with_msg(
+            _run_batch(&src_dirs, &dest_dir, MoveOrCopy::Move, true),
+            "Found 1 conflict",
+        );
+        assert_file_not_moved(
+            src_dirs[0].path().join("only_first"),
+            dest_dir.path().join("only_first"),
+        );
+
+        let work_dir = tempdir().unwrap();
+        let src_paths = [
+            create_temp_file(work_dir.path(), "a/same", "a"),
+            create_temp_file(work_dir.path(), "b/same", "b"),
+        ];
+        assert_error_with_msg(
+            _run_batch(
+                &src_paths,
+                work_dir.path().join("dest/"),
+                MoveOrCopy::Copy,
+                false,
+            ),
+            "same destination",
+        );
+    }
+
+    #[test]
+    fn batch_rejects_resume_with_reflink_always() {
+        let work_dir = tempdir().unwrap();
+        let src = create_temp_file(work_dir.path(), "src", "data");
+        let dest = work_dir.path().join("dest");
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            resume: true,
+            reflink: ReflinkMode::Always,
+            ..test_ctx(MoveOrCopy::Copy, &mp, &ctrlc)
+        };
+        assert_error_with_msg(run_batch([&src], &dest, &ctx), "--reflink=always");
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn batch_aborts_when_a_source_is_its_own_destination() {
+        let work_dir = tempdir().unwrap();
+        let first = work_dir.path().join("first");
+        create_temp_file(&first, "file", "first");
+        let dest = work_dir.path().join("dest");
+        create_temp_file(&dest, "file", "dest");
+
+        // `dest` would be merged into itself after `first` was merged into it.
+        assert_error_with_msg(
+            _run_batch([&first, &dest], &dest, MoveOrCopy::Copy, true),
+            "are the same file",
+        );
+        assert_eq!(fs::read_to_string(dest.join("file")).unwrap(), "dest");
+
+        let a = create_temp_file(work_dir.path(), "a", "a");
+        let b = create_temp_file(work_dir.path(), "b", "b");
+        let linked = work_dir.path().join("linked");
+        fs::create_dir(&linked).unwrap();
+        fs::hard_link(&a, linked.join("a")).unwrap();
+        assert_error_with_msg(
+            _run_batch([&b, &a], &linked, MoveOrCopy::Copy, true),
+            "are the same file",
+        );
+        assert!(!linked.join("b").exists());
+
+        let src = work_dir.path().join("src");
+        create_temp_file(&src, "a_first", "first");
+        create_temp_file(&src, "sub/file", "sub");
+        let merged = work_dir.path().join("merged");
+        fs::create_dir(&merged).unwrap();
+        std::os::unix::fs::symlink(src.join("sub"), merged.join("sub")).unwrap();
+        assert_error_with_msg(
+            _run_batch([&src], &merged, MoveOrCopy::Copy, true),
+            "are the same file",
+        );
+        assert!(!merged.join("a_first").exists());
+    }
+
+    #[test]
+    fn batch_aborts_on_symlink_loop_before_moving_anything() {
+        let work_dir = tempdir().unwrap();
+        let src = work_dir.path().join("src");
+        let file = create_temp_file(&src, "a/f", "content");
+        fs::create_dir(src.join("z")).unwrap();
+        std::os::unix::fs::symlink("..", src.join("z/up")).unwrap();
+        let dest = work_dir.path().join("dest");
+
+        let mp = hidden_multi_progress();
+        let ctrlc = noop_ctrlc();
+        let ctx = Ctx {
+            deref: Dereference::Always,
+            ..test_ctx(MoveOrCopy::Move, &mp, &ctrlc)
+        };
+        assert_error_with_msg(run_batch([&src], &dest, &ctx), "symlink loop");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "content");
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn batch_preserves_hard_links_across_sources() {
+        use std::os::unix::fs::MetadataExt;
+        let work_dir = tempdir().unwrap();
+        let first = create_temp_file(work_dir.path(), "a/file1", "content");
+        fs::create_dir(work_dir.path().join("b")).unwrap();
+        fs::hard_link(&first, work_dir.path().join("b/file2")).unwrap();
+        let dest_dir = tempdir().unwrap();
+
+        _run_batch(
+            [work_dir.path().join("a"), work_dir.path().join("b")],
+            &dest_dir,
+            MoveOrCopy::Copy,
+            false,
+        )
+        .unwrap();
+        let meta = |p: &str| fs::metadata(dest_dir.path().join(p)).unwrap();
+        assert_eq!(meta("file1").ino(), meta("file2").ino());
+
+        let file_dest = work_dir.path().join("files/");
+        _run_batch(
+            [first, work_dir.path().join("b/file2")],
+            &file_dest,
+            MoveOrCopy::Move,
+            false,
+        )
+        .unwrap();
+        let meta = |p: &str| fs::metadata(file_dest.join(p)).unwrap();
+        assert_eq!(meta("file1").ino(), meta("file2").ino());
+        assert_eq!(meta("file1").nlink(), 2);
+    }
+
+    #[test]
+    fn symlink_to_directory_source_is_copied_as_link() {
+        let work_dir = tempdir().unwrap();
+        create_temp_file(work_dir.path(), "target/file1", "content");
+        let link = work_dir.path().join("link");
+        std::os::unix::fs::symlink("target", &link).unwrap();
+        let dest_dir = work_dir.path().join("dest/");
+
+        _run_batch([&link], &dest_dir, MoveOrCopy::Copy, false).unwrap();
+        let dest_link = dest_dir.join("link");
+        assert!(fs::symlink_metadata(&dest_link).unwrap().is_symlink());
+        assert_eq!(fs::read_link(&dest_link).unwrap(), PathBuf::from("target"));
+    }
 }
@@ -0,0 +1,458 @@
+use crate::XattrNamespace;
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+const S_ISUID: u32 = 0o4000;
+const S_ISGID: u32 = 0o2000;
+
+/// Carry `src`'s metadata over to a freshly written `dest`.
+/// Ownership goes first because `chown` clears setuid/setgid and file
+/// capabilities, and timestamps go last, since changing anything else could bump them.
+pub(crate) fn copy_metadata(
+    src: &Path,
+    src_meta: &fs::Metadata,
+    dest: &Path,
+    skip_xattrs: &[XattrNamespace],
+) -> io::Result<()> {
+    copy_ownership(src_meta, dest)?;
+    copy_xattrs(src, dest, skip_xattrs)?;
+    copy_permissions(src_meta, dest)?;
+    copy_times(src_meta, dest)
+}
+
+/// Carry ownership and timestamps over to symlink `dest`. Permission bits
+/// on symlinks are meaningless, and most filesystems reject `user.*` xattrs on them.
+pub(crate) fn copy_symlink_metadata(src_meta: &fs::Metadata, dest: &Path) -> io::Result<()> {
+    copy_ownership(src_meta, dest)?;
+    copy_times(src_meta, dest)
+}
+
+static OWNERSHIP_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Give `dest` the owner and group of `src`, without following a symlink at
+/// `dest`. Root keeps both; other users can only keep the group, and only if
+/// they're a member of it. Warns once per run when ownership can't be kept.
+pub(crate) fn copy_ownership(src: &fs::Metadata, dest: &Path) -> io::Result<()> {
+    let dest_meta = fs::symlink_metadata(dest)?;
+    let uid = (dest_meta.uid() != src.uid()).then_some(src.uid());
+    let gid = (dest_meta.gid() != src.gid()).then_some(src.gid());
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+
+    let privileged = unsafe { libc::geteuid() } == 0;
+    let (uid, gid) = if privileged {
+        (uid, gid)
+    } else {
+        (None, gid.filter(|&gid| in_group(gid)))
+    };
+    let lost = if uid.is_none() && gid.is_none() {
+        true
+    } else {
+        match std::os::unix::fs::lchown(dest, uid, gid) {
+            Ok(()) => !privileged && dest_meta.uid() != src.uid(),
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => true,
+            Err(e) => return Err(e),
+        }
+    };
+    if lost && !OWNERSHIP_WARNED.swap(true, Ordering::Relaxed) {
+        log::warn!(
+            "Could not preserve ownership of '{}' (and possibly others); run as root to keep file owners.",
+            dest.display()
+        );
+    }
+    Ok(())
+}
+
+/// Whether the current process belongs to group `gid`.
+fn in_group(gid: u32) -> bool {
+    if unsafe { libc::getegid() } == gid {
+        return true;
+    }
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let Ok(len) = usize::try_from(count) else {
+        return false;
+    };
+    let mut groups = vec![0; len];
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    usize::try_from(count).is_ok_and(|n| groups[..n.min(len)].contains(&gid))
+}
+
+/// Apply the permission bits of `src` (including sticky) to `dest`.
+/// Setuid/setgid are dropped when `dest`'s owner/group differs from `src`'s,
+/// so a copy never grants the privileges of a different user or group.
+pub(crate) fn copy_permissions(src: &fs::Metadata, dest: &Path) -> io::Result<()> {
+    let dest_meta = fs::metadata(dest)?;
+    let mut mode = src.mode() & 0o7777;
+    if dest_meta.uid() != src.uid() {
+        mode &= !S_ISUID;
+    }
+    if dest_meta.gid() != src.gid() {
+        mode &= !S_ISGID;
+    }
+    fs::set_permissions(dest, fs::Permissions::from_mode(mode))
+}
+
+/// Set `dest`'s access and modification times to `src`'s with nanosecond
+/// precision. A symlink at `dest` is updated itself rather than followed.
+pub(crate) fn copy_times(src: &fs::Metadata, dest: &Path) -> io::Result<()> {
+    let timespec = |sec: i64, nsec: i64| libc::timespec {
+        tv_sec: sec as libc::time_t,
+        tv_nsec: nsec as _,
+    };
+    let times = [
+        timespec(src.atime(), src.atime_nsec()),
+        timespec(src.mtime(), src.mtime_nsec()),
+    ];
+    let path = cstring(dest)?;
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::from)
+}
+
+impl XattrNamespace {
+    /// Classify an attribute name, or `None` for `system.*` attributes other
+    /// than POSIX ACLs, which can't be left out. Names outside the Linux
+    /// namespaces (e.g. `com.apple.*` on macOS) are treated as user attributes.
+    fn of(name: &[u8]) -> Option<Self> {
+        if name == b"system.posix_acl_access" || name == b"system.posix_acl_default" {
+            Some(Self::Acl)
+        } else if name.starts_with(b"system.") {
+            None
+        } else if name.starts_with(b"security.") {
+            Some(Self::Security)
+        } else if name.starts_with(b"trusted.") {
+            Some(Self::Trusted)
+        } else {
+            Some(Self::User)
+        }
+    }
+
+    const fn label(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Acl => "ACL",
+            Self::Security => "security",
+            Self::Trusted => "trusted",
+        }
+    }
+
+    /// Slot in `XATTR_WARNED` for namespace `ns`, with other `system.*`
+    /// attributes (`None`) taking the last one.
+    const fn warn_slot(ns: Option<Self>) -> usize {
+        match ns {
+            Some(Self::User) => 0,
+            Some(Self::Acl) => 1,
+            Some(Self::Security) => 2,
+            Some(Self::Trusted) => 3,
+            None => XATTR_WARN_SLOTS - 1,
+        }
+    }
+}
+
+const XATTR_WARN_SLOTS: usize = 5;
+static XATTR_WARNED: [AtomicBool; XATTR_WARN_SLOTS] =
+    [const { AtomicBool::new(false) }; XATTR_WARN_SLOTS];
+
+/// Copy extended attributes (user xattrs, POSIX ACLs, security labels, file
+/// capabilities) from `src` to `dest`, neither followed if a symlink, except
+/// for namespaces in `skip`. Attributes `dest` can't hold, because its
+/// filesystem lacks support or we lack privileges, are warned about once per
+/// namespace instead of failing the copy.
+pub(crate) fn copy_xattrs(src: &Path, dest: &Path, skip: &[XattrNamespace]) -> io::Result<()> {
+    let src_c = cstring(src)?;
+    let names = match xattr::list(&src_c) {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let dest_c = cstring(dest)?;
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let ns = XattrNamespace::of(name);
+        if ns.is_some_and(|ns| skip.contains(&ns)) {
+            continue;
+        }
+        let name = CString::new(name)?;
+        match xattr::get(&src_c, &name).and_then(|value| xattr::set(&dest_c, &name, &value)) {
+            Ok(()) => {}
+            Err(e) if is_unsupported(&e) || is_denied(&e) => {
+                if !XATTR_WARNED[XattrNamespace::warn_slot(ns)].swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "Could not copy {} extended attributes to '{}' (and possibly others): {e}",
+                        ns.map_or("system", XattrNamespace::label),
+                        dest.display()
+                    );
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP) || e.raw_os_error() == Some(libc::EOPNOTSUPP)
+}
+
+/// Lacking the privileges for a namespace, e.g. `trusted.*` or
+/// `security.*` as an ordinary user.
+fn is_denied(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EPERM | libc::EACCES))
+}
+
+/// Thin wrappers over the platform xattr calls, sizing buffers with a probe call.
+/// None of them follow symlinks.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod xattr {
+    use std::{ffi::CStr, io};
+
+    fn read_sized(call: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        let check = |ret: isize| usize::try_from(ret).map_err(|_| io::Error::last_os_error());
+        let mut buf = vec![0u8; check(call(std::ptr::null_mut(), 0))?];
+        let len = check(call(buf.as_mut_ptr(), buf.len()))?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn list(path: &CStr) -> io::Result<Vec<u8>> {
+        read_sized(|buf, size| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), size) })
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn get(path: &CStr, name: &CStr) -> io::Result<Vec<u8>> {
+        read_sized(|buf, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf.cast(), size)
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn set(path: &CStr, name: &CStr, value: &[u8]) -> io::Result<()> {
+        let ret = unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub(super) fn list(path: &CStr) -> io::Result<Vec<u8>> {
+        read_sized(|buf, size| unsafe {
+            libc::listxattr(path.as_ptr(), buf.cast(), size, libc::XATTR_NOFOLLOW)
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    pub(super) fn get(path: &CStr, name: &CStr) -> io::Result<Vec<u8>> {
+        read_sized(|buf, size| unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.cast(),
+                size,
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    pub(super) fn set(path: &CStr, name: &CStr, value: &[u8]) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod xattr {
+    use std::{ffi::CStr, io};
+
+    pub(super) fn list(_path: &CStr) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    pub(super) fn get(_path: &CStr, _name: &CStr) -> io::Result<Vec<u8>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub(super) fn set(_path: &CStr, _name: &CStr, _value: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_temp_file, is_root, skip_unless};
+    use tempfile::tempdir;
+
+    fn set_xattr(path: &Path, name: &str, value: &[u8]) {
+        let name = CString::new(name).unwrap();
+        xattr::set(&cstring(path).unwrap(), &name, value).unwrap();
+    }
+
+    fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+        let name = CString::new(name).unwrap();
+        xattr::get(&cstring(path).unwrap(), &name).ok()
+    }
+
+    #[test]
+    fn copy_xattrs_carries_user_attributes() {
+        let work_dir = tempdir().unwrap();
+        let src = create_temp_file(work_dir.path(), "a", "content");
+        let dest = create_temp_file(work_dir.path(), "b", "content");
+        set_xattr(&src, "user.origin", b"camera");
+        set_xattr(&src, "user.empty", b"");
+
+        copy_xattrs(&src, &dest, &[]).unwrap();
+        assert_eq!(
+            get_xattr(&dest, "user.origin").as_deref(),
+            Some(&b"camera"[..])
+        );
+        assert_eq!(get_xattr(&dest, "user.empty").as_deref(), Some(&b""[..]));
+    }
+
+    #[test]
+    fn copy_xattrs_skips_excluded_namespaces() {
+        let work_dir = tempdir().unwrap();
+        let src = create_temp_file(work_dir.path(), "a", "content");
+        let dest = create_temp_file(work_dir.path(), "b", "content");
+        set_xattr(&src, "user.origin", b"camera");
+
+        copy_xattrs(&src, &dest, &[XattrNamespace::User]).unwrap();
+        assert_eq!(get_xattr(&dest, "user.origin"), None);
+    }
+
+    #[test]
+    fn copy_permissions_drops_setid_bits_of_another_owner() {
+        skip_unless!(!is_root(), "root's copies keep the source owner");
+        // Only root can give a file setuid/setgid bits for someone else, so
+        // borrow system ones: one owned by another user, one by another group.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let find = |bit: u32, foreign: &dyn Fn(&fs::Metadata) -> bool| {
+            ["/usr/bin", "/bin", "/usr/sbin"]
+                .into_iter()
+                .flat_map(|dir| fs::read_dir(dir).into_iter().flatten().flatten())
+                .filter_map(|entry| entry.metadata().ok())
+                .find(|m| m.is_file() && m.mode() & bit != 0 && foreign(m))
+        };
+        let setuid = find(S_ISUID, &|m| m.uid() != uid);
+        let setgid = find(S_ISGID, &|m| m.gid() != gid);
+        skip_unless!(
+            setuid.is_some() && setgid.is_some(),
+            "no setuid and setgid files of another user and group found"
+        );
+        let work_dir = tempdir().unwrap();
+
+        for (src_meta, bit) in [(setuid.unwrap(), S_ISUID), (setgid.unwrap(), S_ISGID)] {
+            let dest = create_temp_file(work_dir.path(), "a", "content");
+            copy_permissions(&src_meta, &dest).unwrap();
+            let mode = fs::metadata(&dest).unwrap().mode();
+            assert_eq!(mode & bit, 0, "{:o}", src_meta.mode());
+            assert_eq!(mode & 0o777, src_meta.mode() & 0o777);
+        }
+    }
+
+    #[test]
+    fn copy_xattrs_carries_trusted_attributes_as_root() {
+        skip_unless!(is_root(), "trusted.* is only visible to root");
+        let work_dir = tempdir().unwrap();
+        let src = create_temp_file(work_dir.path(), "a", "content");
+        let dest = create_temp_file(work_dir.path(), "b", "content");
+        set_xattr(&src, "trusted.overlay.opaque", b"y");
+
+        copy_xattrs(&src, &dest, &[XattrNamespace::User]).unwrap();
+        assert_eq!(
+            get_xattr(&dest, "trusted.overlay.opaque").as_deref(),
+            Some(&b"y"[..])
+        );
+    }
+
+    #[test]
+    fn warn_slots_are_distinct() {
+        let mut slots: Vec<_> = [
+            Some(XattrNamespace::User),
+            Some(XattrNamespace::Acl),
+            Some(XattrNamespace::Security),
+            Some(XattrNamespace::Trusted),
+            None,
+        ]
+        .map(XattrNamespace::warn_slot)
+        .to_vec();
+        slots.sort_unstable();
+        slots.dedup();
+        assert_eq!(slots.len(), XATTR_WARN_SLOTS);
+        assert!(slots.iter().all(|&slot| slot < XATTR_WARN_SLOTS));
+    }
+
+    #[test]
+    fn namespace_classification() {
+        assert_eq!(XattrNamespace::of(b"user.x"), Some(XattrNamespace::User));
+        assert_eq!(
+            XattrNamespace::of(b"system.posix_acl_access"),
+            Some(XattrNamespace::Acl)
+        );
+        assert_eq!(
+            XattrNamespace::of(b"system.posix_acl_default"),
+            Some(XattrNamespace::Acl)
+        );
+        assert_eq!(XattrNamespace::of(b"system.nfs4_acl"), None);
+        assert_eq!(XattrNamespace::of(b"system.posix_acl_accessx"), None);
+        assert_eq!(
+            XattrNamespace::of(b"security.capability"),
+            Some(XattrNamespace::Security)
+        );
+        assert_eq!(
+            XattrNamespace::of(b"trusted.x"),
+            Some(XattrNamespace::Trusted)
+        );
+        assert_eq!(
+            XattrNamespace::of(b"com.apple.quarantine"),
+            Some(XattrNamespace::User)
+        );
+    }
+}